@@ -0,0 +1,77 @@
+use crate::Color;
+
+/// How `Canvas` combines the colors it draws with what's already there.
+/// In every mode, the result is mixed with the original color according to
+/// the alpha of the new one, so fully transparent colors change nothing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlendMode {
+    // The new color is painted over the old one. Opaque colors replace it.
+    SourceOver,
+    // The colors are added, which brightens. Good for glows and light.
+    Additive,
+    // The colors are multiplied, which darkens. Good for tints and shadows.
+    Multiply,
+    // The inverse of multiplying the inverted colors, which brightens
+    // without saturating as quickly as adding.
+    Screen,
+}
+
+impl BlendMode {
+    // Draws `src` over `dst`, with the alpha of `src` scaled by `coverage`.
+    // The result is opaque.
+    pub fn blend(&self, src: Color, dst: Color, coverage: f32) -> Color {
+        let alpha = src.a as f32 / 255. * coverage;
+        let s = [src.r, src.g, src.b].map(|c| (c as f32 * src.h).min(255.) / 255.);
+        let d = [dst.r, dst.g, dst.b].map(|c| (c as f32 * dst.h).min(255.) / 255.);
+        let out: [u8; 3] = std::array::from_fn(|k| {
+            let (s, d) = (s[k], d[k]);
+            let blended = match self {
+                BlendMode::SourceOver => s,
+                BlendMode::Additive => (s + d).min(1.),
+                BlendMode::Multiply => s * d,
+                BlendMode::Screen => 1. - (1. - s) * (1. - d),
+            };
+            ((d + (blended - d) * alpha) * 255.).round() as u8
+        });
+        Color::rgb(out[0], out[1], out[2])
+    }
+}
+
+#[test]
+fn test_blend() {
+    let dst = Color::rgb(100, 200, 50);
+    let src = Color::rgba(200, 100, 255, 255);
+    let channels = |c: Color| (c.r, c.g, c.b, c.a);
+
+    assert_eq!(
+        channels(BlendMode::SourceOver.blend(src, dst, 1.)),
+        (200, 100, 255, 255)
+    );
+    assert_eq!(
+        channels(BlendMode::SourceOver.blend(src, dst, 0.5)),
+        (150, 150, 153, 255)
+    );
+    assert_eq!(
+        channels(BlendMode::Additive.blend(src, dst, 1.)),
+        (255, 255, 255, 255)
+    );
+    assert_eq!(
+        channels(BlendMode::Multiply.blend(src, dst, 1.)),
+        (78, 78, 50, 255)
+    );
+    assert_eq!(
+        channels(BlendMode::Screen.blend(src, dst, 1.)),
+        (222, 222, 255, 255)
+    );
+
+    // Transparent colors don't change anything, whatever the mode.
+    let clear = Color::rgba(255, 255, 255, 0);
+    for mode in [
+        BlendMode::SourceOver,
+        BlendMode::Additive,
+        BlendMode::Multiply,
+        BlendMode::Screen,
+    ] {
+        assert_eq!(channels(mode.blend(clear, dst, 1.)), (100, 200, 50, 255));
+    }
+}
@@ -0,0 +1,148 @@
+use crate::math::*;
+use crate::scene::Triangle;
+
+/// A plane defined by a unit normal and its signed distance from the origin,
+/// i.e. the set of points P where dot(normal, P) + distance = 0.
+#[derive(Debug, Clone, Copy)]
+pub struct Plane {
+    pub normal: Point3,
+    pub distance: f32,
+}
+
+impl Plane {
+    pub fn new(normal: Point3, distance: f32) -> Self {
+        Self {
+            normal: normal.normalize(),
+            distance,
+        }
+    }
+
+    /// Positive for points on the side the normal points to (inside the
+    /// clipping volume), negative for points on the other side.
+    pub fn signed_distance(&self, p: Point3) -> f32 {
+        self.normal.dot(p) + self.distance
+    }
+
+    // How far along the segment AB it crosses the plane, from 0 at A to 1
+    // at B.
+    fn intersection(&self, a: Point3, b: Point3) -> f32 {
+        let da = self.signed_distance(a);
+        let db = self.signed_distance(b);
+        da / (da - db)
+    }
+}
+
+// From Chapter 11. Clips the triangles against a single plane, discarding
+// those that are completely outside and splitting those that straddle it.
+// New vertices are appended to `vertices`, and the per-corner attributes of
+// the triangles are interpolated accordingly.
+pub fn clip_triangles(
+    triangles: &[Triangle],
+    vertices: &mut Vec<Point3>,
+    plane: &Plane,
+) -> Vec<Triangle> {
+    let mut clipped = Vec::new();
+    for t in triangles {
+        let (i0, i1, i2) = t.v;
+        let inside = [i0, i1, i2].map(|i| plane.signed_distance(vertices[i]) >= 0.);
+
+        // Rotate the triangle without changing the winding order, so that
+        // the odd one out (if any) ends up first.
+        let t = match inside {
+            [true, true, true] => {
+                clipped.push(*t);
+                continue;
+            }
+            [false, false, false] => continue,
+            [true, false, false] | [false, true, true] => t.rotated(0),
+            [false, true, false] | [true, false, true] => t.rotated(1),
+            [false, false, true] | [true, true, false] => t.rotated(2),
+        };
+        let (a, b, c) = t.v;
+
+        let s_ab = plane.intersection(vertices[a], vertices[b]);
+        let s_ac = plane.intersection(vertices[a], vertices[c]);
+        let ab = vertices.len();
+        vertices.push(vertices[a] + (vertices[b] - vertices[a]) * s_ab);
+        let ac = vertices.len();
+        vertices.push(vertices[a] + (vertices[c] - vertices[a]) * s_ac);
+        let uv_ab = lerp_uv(t.uvs[0], t.uvs[1], s_ab);
+        let uv_ac = lerp_uv(t.uvs[0], t.uvs[2], s_ac);
+        let split = |n: [Point3; 3]| {
+            let n_ab = n[0] + (n[1] - n[0]) * s_ab;
+            let n_ac = n[0] + (n[2] - n[0]) * s_ac;
+            (n, n_ab, n_ac)
+        };
+        let normals = t.normals.map(split);
+        let tangents = t.tangents.map(split);
+
+        if plane.signed_distance(vertices[a]) >= 0. {
+            // Only A is inside: the result is a smaller triangle.
+            clipped.push(Triangle {
+                v: (a, ab, ac),
+                uvs: [t.uvs[0], uv_ab, uv_ac],
+                normals: normals.map(|(n, n_ab, n_ac)| [n[0], n_ab, n_ac]),
+                tangents: tangents.map(|(n, n_ab, n_ac)| [n[0], n_ab, n_ac]),
+                ..t
+            });
+        } else {
+            // Only A is outside: the result is a quad, split in two.
+            clipped.push(Triangle {
+                v: (ab, b, c),
+                uvs: [uv_ab, t.uvs[1], t.uvs[2]],
+                normals: normals.map(|(n, n_ab, _)| [n_ab, n[1], n[2]]),
+                tangents: tangents.map(|(n, n_ab, _)| [n_ab, n[1], n[2]]),
+                ..t
+            });
+            clipped.push(Triangle {
+                v: (ab, c, ac),
+                uvs: [uv_ab, t.uvs[2], uv_ac],
+                normals: normals.map(|(n, n_ab, n_ac)| [n_ab, n[2], n_ac]),
+                tangents: tangents.map(|(n, n_ab, n_ac)| [n_ab, n[2], n_ac]),
+                ..t
+            });
+        }
+    }
+    clipped
+}
+
+fn lerp_uv(a: (f32, f32), b: (f32, f32), t: f32) -> (f32, f32) {
+    (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t)
+}
+
+#[test]
+fn test_clip_triangles() {
+    let near = Plane::new(Point3::new(0., 0., 1.), -1.);
+    let mut vertices = vec![
+        Point3::new(0., 0., 2.),
+        Point3::new(1., 0., 0.),
+        Point3::new(0., 1., 0.),
+        Point3::new(0., 0., 3.),
+    ];
+    let triangles = [Triangle::new((0, 1, 2), 0u32).with_uvs([(0., 0.), (1., 0.), (0., 1.)])];
+
+    // One vertex inside: the triangle shrinks, keeping its winding order.
+    let clipped = clip_triangles(&triangles, &mut vertices, &near);
+    assert_eq!(clipped.len(), 1);
+    assert_eq!(clipped[0].v, (0, 4, 5));
+    assert_eq!(clipped[0].uvs, [(0., 0.), (0.5, 0.), (0., 0.5)]);
+    for i in [4, 5] {
+        assert!(near.signed_distance(vertices[i]).abs() < 1e-6);
+    }
+
+    // Two vertices inside: the resulting quad is split in two.
+    let triangles = [Triangle::new((0, 3, 1), 0u32)];
+    let clipped = clip_triangles(&triangles, &mut vertices, &near);
+    assert_eq!(clipped.len(), 2);
+    assert_eq!(clipped[0].v, (6, 0, 3));
+    assert_eq!(clipped[1].v, (6, 3, 7));
+
+    // Fully inside or fully outside.
+    let triangles = [
+        Triangle::new((0, 3, 4), 0u32),
+        Triangle::new((1, 2, 1), 0u32),
+    ];
+    let clipped = clip_triangles(&triangles, &mut vertices, &near);
+    assert_eq!(clipped.len(), 1);
+    assert_eq!(clipped[0].v, (0, 3, 4));
+}
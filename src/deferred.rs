@@ -0,0 +1,159 @@
+use std::thread;
+
+use crate::light::*;
+use crate::math::*;
+use crate::shading::*;
+
+/// How `Scene::render` lights what it rasterizes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pipeline {
+    // Each pixel is lit as soon as it's drawn, even if something closer is
+    // drawn over it later.
+    Forward,
+    // All the opaque geometry is rasterized into a `GBuffer` first, then
+    // each light is applied to the pixels that are left, so that the cost
+    // of lighting doesn't depend on how much geometry there is. Lighting is
+    // always computed per pixel, and multisampling only anti-aliases
+    // translucent models, since the G-buffer has one sample per pixel.
+    Deferred,
+}
+
+/// The properties of a surface that lighting depends on, other than its
+/// position, normal and color.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Material {
+    pub specular: Option<f32>,
+}
+
+/// Everything needed to light the pixels of a canvas after the geometry
+/// has been rasterized, in planes the size of the canvas, with the pixels in
+/// the same order as its `data`.
+pub struct GBuffer {
+    pub width: usize,
+    pub height: usize,
+    // In camera space.
+    pub positions: Vec<Point3>,
+    pub normals: Vec<Point3>,
+    // The color of the surface before lighting.
+    pub albedo: Vec<u32>,
+    // 0 where nothing was drawn, or the index in `materials` plus one.
+    pub material_ids: Vec<u16>,
+    pub materials: Vec<Material>,
+}
+
+impl GBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        let n = width * height;
+        Self {
+            width,
+            height,
+            positions: vec![Point3::default(); n],
+            normals: vec![Point3::default(); n],
+            albedo: vec![0; n],
+            material_ids: vec![0; n],
+            materials: Vec::new(),
+        }
+    }
+
+    // The ID to store for pixels with the given material, which is added to
+    // `materials` if it isn't there yet.
+    pub fn material_id(&mut self, material: Material) -> u16 {
+        let i = match self.materials.iter().position(|m| *m == material) {
+            Some(i) => i,
+            None => {
+                self.materials.push(material);
+                self.materials.len() - 1
+            }
+        };
+        i as u16 + 1
+    }
+
+    pub fn set(
+        &mut self,
+        i: usize,
+        position: Point3,
+        normal: Point3,
+        albedo: u32,
+        material_id: u16,
+    ) {
+        self.positions[i] = position;
+        self.normals[i] = normal;
+        self.albedo[i] = albedo;
+        self.material_ids[i] = material_id;
+    }
+
+    pub fn material(&self, i: usize) -> Option<&Material> {
+        match self.material_ids[i] {
+            0 => None,
+            id => Some(&self.materials[id as usize - 1]),
+        }
+    }
+
+    // The lighting pass: the intensity of the light reaching the camera from
+    // each pixel (see `compute_lighting`), with the lights (in camera space)
+    // applied one after the other. `visibility` gives the fraction of light
+    // `i` that reaches a point with a given normal. The pixels are split
+    // between `threads` threads.
+    pub fn light(
+        &self,
+        lights: &[Light],
+        visibility: impl Fn(usize, Point3, Point3) -> f32 + Sync,
+        threads: usize,
+    ) -> Vec<[f32; 3]> {
+        let mut intensity = vec![[0.; 3]; self.material_ids.len()];
+        let chunk_size = intensity.len().div_ceil(threads.max(1)).max(1);
+        thread::scope(|s| {
+            for (chunk, pixels) in intensity.chunks_mut(chunk_size).enumerate() {
+                let visibility = &visibility;
+                s.spawn(move || {
+                    let start = chunk * chunk_size;
+                    for (l, light) in lights.iter().enumerate() {
+                        for (k, pixel) in pixels.iter_mut().enumerate() {
+                            let i = start + k;
+                            let Some(material) = self.material(i) else {
+                                continue;
+                            };
+                            let (p, n) = (self.positions[i], self.normals[i]);
+                            let rgb = compute_lighting(
+                                p,
+                                n,
+                                material.specular,
+                                std::slice::from_ref(light),
+                                |_| visibility(l, p, n),
+                            );
+                            for c in 0..3 {
+                                pixel[c] += rgb[c];
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        intensity
+    }
+}
+
+#[test]
+fn test_gbuffer() {
+    let mut gbuffer = GBuffer::new(2, 1);
+    let matte = gbuffer.material_id(Material { specular: None });
+    let shiny = gbuffer.material_id(Material {
+        specular: Some(10.),
+    });
+    assert_eq!((matte, shiny), (1, 2));
+    assert_eq!(gbuffer.material_id(Material { specular: None }), matte);
+
+    // A single pixel, facing a light right in front of it.
+    let (point, normal) = (Point3::new(0., 0., 5.), Point3::new(0., 0., -1.));
+    gbuffer.set(1, point, normal, 0xFFFFFF, matte);
+    let lights = [
+        Light::ambient(0.2),
+        Light::point(0.5, Point3::new(0., 0., 0.)),
+        Light::point(0.3, Point3::new(0., 0., 0.)),
+    ];
+    let intensity = gbuffer.light(&lights, |_, _, _| 1., 2);
+    assert_eq!(intensity[0], [0.; 3]);
+    assert!((intensity[1][0] - 1.).abs() < 1e-6);
+    let shadowed = gbuffer.light(&lights, |i, _, _| if i == 2 { 0. } else { 1. }, 1);
+    assert!((shadowed[1][0] - 0.7).abs() < 1e-6);
+}
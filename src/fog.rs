@@ -0,0 +1,95 @@
+use crate::Color;
+
+/// How thick fog gets with depth.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FogMode {
+    // None up to depth `start`, then thicker and thicker until nothing but
+    // fog can be seen from depth `end` on.
+    Linear { start: f32, end: f32 },
+    // A fraction e^(-density * z) of the color shows through at depth z.
+    Exponential { density: f32 },
+    // Likewise with e^(-(density * z)^2), which stays clearer up close and
+    // then thickens faster.
+    ExponentialSquared { density: f32 },
+}
+
+/// Blends what's drawn towards `color` the further it is from the camera,
+/// which gives a sense of depth even without lighting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fog {
+    pub mode: FogMode,
+    pub color: u32,
+}
+
+impl Fog {
+    // How much of the color of something at depth `z` is replaced by that of
+    // the fog, from 0 to 1.
+    pub fn amount(&self, z: f32) -> f32 {
+        let clear = match self.mode {
+            FogMode::Linear { start, end } => {
+                if end > start {
+                    (end - z) / (end - start)
+                } else if z < start {
+                    1.
+                } else {
+                    0.
+                }
+            }
+            FogMode::Exponential { density } => (-density * z).exp(),
+            FogMode::ExponentialSquared { density } => (-(density * z).powi(2)).exp(),
+        };
+        1. - clear.clamp(0., 1.)
+    }
+
+    // `color` as seen through the fog at depth `z`, with the same alpha.
+    pub fn apply(&self, color: Color, z: f32) -> Color {
+        let f = self.amount(z);
+        let fog = Color::from(self.color);
+        let mix = |c: u8, fog: u8| {
+            let c = (c as f32 * color.h).min(255.);
+            (c + (fog as f32 - c) * f).round() as u8
+        };
+        Color::rgba(
+            mix(color.r, fog.r),
+            mix(color.g, fog.g),
+            mix(color.b, fog.b),
+            color.a,
+        )
+    }
+}
+
+#[test]
+fn test_fog() {
+    let linear = Fog {
+        mode: FogMode::Linear {
+            start: 10.,
+            end: 20.,
+        },
+        color: 0xFFFFFF,
+    };
+    assert_eq!(linear.amount(5.), 0.);
+    assert_eq!(linear.amount(15.), 0.5);
+    assert_eq!(linear.amount(25.), 1.);
+
+    let exp = Fog {
+        mode: FogMode::Exponential { density: 0.1 },
+        ..linear
+    };
+    let exp2 = Fog {
+        mode: FogMode::ExponentialSquared { density: 0.1 },
+        ..linear
+    };
+    assert_eq!(exp.amount(0.), 0.);
+    assert!((exp.amount(10.) - (1. - (-1_f32).exp())).abs() < 1e-6);
+    // Squaring keeps it clearer up close, and makes it thicker further away.
+    assert!(exp2.amount(5.) < exp.amount(5.));
+    assert!(exp2.amount(20.) > exp.amount(20.));
+
+    let mut color = Color::rgba(100, 0, 200, 128);
+    color.mul(0.5);
+    let fogged = linear.apply(color, 15.);
+    assert_eq!(
+        (fogged.r, fogged.g, fogged.b, fogged.a),
+        (153, 128, 178, 128)
+    );
+}
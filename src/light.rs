@@ -0,0 +1,113 @@
+use crate::math::*;
+use crate::Color;
+
+#[derive(Debug, Clone, Copy)]
+pub enum LightKind {
+    // Lights every point equally, regardless of its position or orientation.
+    Ambient,
+    // Emits light in every direction from the given position.
+    Point(Point3),
+    // Shines from infinitely far away, i.e. the light comes from the given
+    // direction everywhere.
+    Directional(Point3),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Light {
+    pub kind: LightKind,
+    pub intensity: f32,
+    // White if None.
+    pub color: Option<u32>,
+    // Whether objects block this light, which needs a shadow map.
+    pub casts_shadows: bool,
+}
+
+impl Light {
+    pub fn ambient(intensity: f32) -> Self {
+        Self {
+            kind: LightKind::Ambient,
+            intensity,
+            color: None,
+            casts_shadows: false,
+        }
+    }
+
+    pub fn point(intensity: f32, position: Point3) -> Self {
+        Self {
+            kind: LightKind::Point(position),
+            intensity,
+            color: None,
+            casts_shadows: false,
+        }
+    }
+
+    pub fn directional(intensity: f32, direction: Point3) -> Self {
+        Self {
+            kind: LightKind::Directional(direction),
+            intensity,
+            color: None,
+            casts_shadows: false,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_color(self, color: u32) -> Self {
+        Self {
+            color: Some(color),
+            ..self
+        }
+    }
+
+    pub fn with_shadows(self) -> Self {
+        Self {
+            casts_shadows: true,
+            ..self
+        }
+    }
+
+    // The intensity of the light in each of the red, green and blue channels.
+    pub fn rgb_intensity(&self) -> [f32; 3] {
+        match self.color {
+            None => [self.intensity; 3],
+            Some(c) => {
+                let c = Color::from(c);
+                [c.r, c.g, c.b].map(|c| self.intensity * c as f32 / 255.)
+            }
+        }
+    }
+
+    // The same light, with its position or direction transformed by `m`.
+    pub fn transformed(&self, m: Matrix4) -> Self {
+        let kind = match self.kind {
+            LightKind::Ambient => LightKind::Ambient,
+            LightKind::Point(position) => LightKind::Point(m * position),
+            LightKind::Directional(direction) => LightKind::Directional(m.mul_dir(direction)),
+        };
+        Self { kind, ..*self }
+    }
+}
+
+#[test]
+fn test_transformed() {
+    let m = Matrix4::from_translation(Point3::new(1., 2., 3.));
+    let LightKind::Point(p) = Light::point(1., Point3::new(1., 1., 1.))
+        .transformed(m)
+        .kind
+    else {
+        panic!();
+    };
+    assert_eq!((p.x, p.y, p.z), (2., 3., 4.));
+    let LightKind::Directional(d) = Light::directional(1., Point3::new(1., 1., 1.))
+        .transformed(m)
+        .kind
+    else {
+        panic!();
+    };
+    assert_eq!((d.x, d.y, d.z), (1., 1., 1.));
+    assert_eq!(
+        Light::ambient(0.5)
+            .with_color(Color::red().into())
+            .rgb_intensity(),
+        [0.5, 0., 0.]
+    );
+}
@@ -0,0 +1,285 @@
+use crate::math::*;
+
+// Miters longer than this many times the line width are beveled instead, as
+// in SVG.
+const MITER_LIMIT: f32 = 4.;
+
+/// How the ends of a thick line are drawn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineCap {
+    // The line stops exactly at its end points.
+    Butt,
+    // The line extends past its end points by half its width.
+    Square,
+    // Each end is a half disc centered on the end point.
+    Round,
+}
+
+/// How two consecutive segments of a thick polyline are connected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineJoin {
+    // The outer edges are extended until they meet, unless that would be
+    // too far away, in which case the corner is beveled.
+    Miter,
+    // The corner is cut off.
+    Bevel,
+    // The corner is rounded.
+    Round,
+}
+
+/// How `Canvas` draws lines, including the edges of wireframe triangles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineStyle {
+    // Blend the pixels on the edges of the line according to how much of
+    // them it covers, instead of drawing them or not.
+    pub anti_aliased: bool,
+    pub width: f32,
+    pub cap: LineCap,
+    pub join: LineJoin,
+}
+
+impl Default for LineStyle {
+    fn default() -> Self {
+        Self {
+            anti_aliased: false,
+            width: 1.,
+            cap: LineCap::Butt,
+            join: LineJoin::Miter,
+        }
+    }
+}
+
+impl LineStyle {
+    // How much of the pixel at distance `d` from a shape (negative inside)
+    // is covered by it, between 0 and 1.
+    pub fn coverage(&self, d: f32) -> f32 {
+        if self.anti_aliased {
+            (0.5 - d).clamp(0., 1.)
+        } else if d <= 0. {
+            1.
+        } else {
+            0.
+        }
+    }
+
+    // How far the pixels drawn for a polyline can be from its points, e.g.
+    // the tip of a miter.
+    pub fn reach(&self) -> f32 {
+        let hw = self.width / 2.;
+        let join = match self.join {
+            LineJoin::Miter => hw * MITER_LIMIT,
+            LineJoin::Bevel | LineJoin::Round => hw,
+        };
+        let cap = match self.cap {
+            LineCap::Square => hw * std::f32::consts::SQRT_2,
+            LineCap::Butt | LineCap::Round => hw,
+        };
+        // Anti-aliasing partly covers the pixels up to half a pixel out.
+        let aa = if self.anti_aliased { 0.5 } else { 0. };
+        join.max(cap) + aa
+    }
+}
+
+/// One of the convex pieces that make up a thick line.
+#[derive(Debug, Clone)]
+pub enum Shape {
+    // All the points within `radius` of the segment from `a` to `b`.
+    Capsule(Point3, Point3, f32),
+    // A convex polygon, in either winding.
+    Polygon(Vec<Point3>),
+}
+
+impl Shape {
+    // The (approximate) signed distance from (x, y) to the shape, which is
+    // negative inside. It's exact near the edges, which is all that matters
+    // for anti-aliasing.
+    pub fn distance(&self, x: f32, y: f32) -> f32 {
+        let p = Point3::new(x, y, 0.);
+        match self {
+            Shape::Capsule(a, b, radius) => {
+                let ab = *b - *a;
+                let len2 = ab.dot(ab);
+                let t = if len2 > 0. {
+                    ((p - *a).dot(ab) / len2).clamp(0., 1.)
+                } else {
+                    0.
+                };
+                (p - (*a + ab * t)).length() - radius
+            }
+            Shape::Polygon(points) => {
+                let n = points.len();
+                let area: f32 = (0..n).map(|i| points[i].cross(points[(i + 1) % n]).z).sum();
+                let sign = if area < 0. { -1. } else { 1. };
+                (0..n)
+                    .filter_map(|i| {
+                        let e = points[(i + 1) % n] - points[i];
+                        let len = e.length();
+                        (len > 0.).then(|| {
+                            let outward = Point3::new(e.y, -e.x, 0.) * (sign / len);
+                            (p - points[i]).dot(outward)
+                        })
+                    })
+                    .fold(f32::MIN, f32::max)
+            }
+        }
+    }
+
+    // The smallest rectangle of pixels outside of which the shape covers
+    // nothing, even when anti-aliased.
+    pub fn bounds(&self) -> Rect {
+        let (points, margin) = match self {
+            Shape::Capsule(a, b, radius) => (vec![*a, *b], radius + 1.),
+            Shape::Polygon(points) => (points.clone(), 1.),
+        };
+        let min = |f: fn(&Point3) -> f32| points.iter().map(f).fold(f32::MAX, f32::min);
+        let max = |f: fn(&Point3) -> f32| points.iter().map(f).fold(f32::MIN, f32::max);
+        Rect::new(
+            (min(|p| p.x) - margin).floor() as i32,
+            (min(|p| p.y) - margin).floor() as i32,
+            (max(|p| p.x) + margin).ceil() as i32,
+            (max(|p| p.y) + margin).ceil() as i32,
+        )
+    }
+}
+
+fn to_point3(p: &Point2) -> Point3 {
+    Point3::new(p.x as f32, p.y as f32, 0.)
+}
+
+/// Breaks the polyline through `points` (a polygon if `closed`), drawn with
+/// the given style, into convex shapes. The shapes overlap, so a pixel is
+/// covered by the line as much as by the closest of them.
+pub fn stroke(points: &[Point2], closed: bool, style: &LineStyle) -> Vec<Shape> {
+    let hw = style.width / 2.;
+    let points: Vec<Point3> = points.iter().map(to_point3).collect();
+    let n = points.len();
+    let segment_count = if closed && n > 2 {
+        n
+    } else {
+        n.saturating_sub(1)
+    };
+
+    // The segments, along with their directions and left-hand normals.
+    // Zero-length ones are skipped since they don't have a direction.
+    let segments: Vec<(Point3, Point3, Point3, Point3)> = (0..segment_count)
+        .filter_map(|i| {
+            let (a, b) = (points[i], points[(i + 1) % n]);
+            let len = (b - a).length();
+            (len > 0.).then(|| {
+                let u = (b - a) * (1. / len);
+                (a, b, u, Point3::new(-u.y, u.x, 0.))
+            })
+        })
+        .collect();
+
+    let mut shapes = Vec::new();
+    for &(a, b, _, normal) in &segments {
+        let offset = normal * hw;
+        shapes.push(Shape::Polygon(vec![
+            a + offset,
+            a - offset,
+            b - offset,
+            b + offset,
+        ]));
+    }
+
+    // Caps, at both ends of open polylines.
+    if let (false, Some(first), Some(last)) = (closed, segments.first(), segments.last()) {
+        for (p, outward) in [(first.0, -first.2), (last.1, last.2)] {
+            let offset = Point3::new(-outward.y, outward.x, 0.) * hw;
+            match style.cap {
+                LineCap::Butt => {}
+                LineCap::Square => shapes.push(Shape::Polygon(vec![
+                    p + offset,
+                    p - offset,
+                    p - offset + outward * hw,
+                    p + offset + outward * hw,
+                ])),
+                LineCap::Round => shapes.push(Shape::Capsule(p, p, hw)),
+            }
+        }
+    }
+
+    // Joins, between each pair of consecutive segments.
+    let join_count = if closed {
+        segments.len()
+    } else {
+        segments.len().saturating_sub(1)
+    };
+    for i in 0..join_count {
+        let (_, v, u1, n1) = segments[i];
+        let (_, _, u2, n2) = segments[(i + 1) % segments.len()];
+        let turn = u1.cross(u2).z;
+        if turn == 0. && u1.dot(u2) > 0. {
+            continue;
+        }
+
+        // The gap to fill is on the outside of the turn.
+        let side = if turn > 0. { -1. } else { 1. };
+        let (p1, p2) = (v + n1 * (hw * side), v + n2 * (hw * side));
+        let miter = (n1 + n2).normalize() * side;
+        let miter_ratio = 1. / miter.dot(n1 * side);
+        match style.join {
+            LineJoin::Round => shapes.push(Shape::Capsule(v, v, hw)),
+            LineJoin::Miter if miter_ratio <= MITER_LIMIT => shapes.push(Shape::Polygon(vec![
+                v,
+                p1,
+                v + miter * (hw * miter_ratio),
+                p2,
+            ])),
+            LineJoin::Miter | LineJoin::Bevel => {
+                if turn != 0. {
+                    shapes.push(Shape::Polygon(vec![v, p1, p2]));
+                }
+            }
+        }
+    }
+    shapes
+}
+
+#[test]
+fn test_stroke() {
+    let style = LineStyle {
+        width: 2.,
+        ..Default::default()
+    };
+    let corner = [Point2::new(0, 0), Point2::new(10, 0), Point2::new(10, 10)];
+    let distance = |shapes: &[Shape], x, y| {
+        shapes
+            .iter()
+            .map(|s| s.distance(x, y))
+            .fold(f32::MAX, f32::min)
+    };
+
+    // A miter fills the outer corner; a bevel cuts it off halfway.
+    let mitered = stroke(&corner, false, &style);
+    assert_eq!(mitered.len(), 3);
+    assert!(distance(&mitered, 10.9, -0.9) < 0.);
+    let beveled = stroke(
+        &corner,
+        false,
+        &LineStyle {
+            join: LineJoin::Bevel,
+            ..style
+        },
+    );
+    assert!(distance(&beveled, 10.9, -0.9) > 0.);
+    assert!(distance(&beveled, 10.4, -0.4) < 0.);
+
+    // Butt caps stop at the end points, square and round ones don't.
+    assert!(distance(&mitered, -0.5, 0.) > 0.);
+    for cap in [LineCap::Square, LineCap::Round] {
+        let shapes = stroke(&corner, false, &LineStyle { cap, ..style });
+        assert_eq!(shapes.len(), 5);
+        assert!(distance(&shapes, -0.5, 0.) < 0.);
+        assert!(distance(&shapes, 10., 10.5) < 0.);
+    }
+
+    // Closed polylines get a join at every vertex, and no caps.
+    assert_eq!(stroke(&corner, true, &style).len(), 6);
+
+    // A sharp turn exceeds the miter limit.
+    let sharp = [Point2::new(0, 0), Point2::new(100, 0), Point2::new(0, 5)];
+    let shapes = stroke(&sharp, false, &style);
+    assert!(matches!(&shapes[2], Shape::Polygon(p) if p.len() == 3));
+}
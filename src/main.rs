@@ -1,22 +1,61 @@
-use minifb::{Key, Window, WindowOptions};
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
 use std::fmt;
-use std::rc::Rc;
+use std::sync::Arc;
 use std::thread::sleep;
 use std::time::Duration;
 
+mod blend;
+use blend::*;
+
+mod clip;
+
+mod deferred;
+use deferred::*;
+
+mod fog;
+use fog::*;
+
+mod light;
+use light::*;
+
+mod line;
+use line::*;
+
 mod math;
 use math::*;
 
+mod raster;
+use raster::*;
+
+mod raytracer;
+use raytracer::*;
+
 mod scene;
 use scene::*;
 
+mod shading;
+use shading::*;
+
+mod shadow;
+use shadow::*;
+
+mod stencil;
+use stencil::*;
+
+mod texture;
+use texture::*;
+
 use std::f32::consts::PI;
 
 const WIDTH: usize = 600;
 const HEIGHT: usize = 600;
 
 fn main() {
+    // What the window shows; the scene is rendered to `canvas` first, which
+    // is larger when supersampling.
+    let mut screen = Canvas::new(WIDTH, HEIGHT);
     let mut canvas = Canvas::new(WIDTH, HEIGHT);
+    let mut antialiasing = Antialiasing::Off;
 
     let mut window = Window::new(
         "Test - ESC to exit",
@@ -28,6 +67,7 @@ fn main() {
 
     let mut scene = Scene::new(1, 1);
     init_cube_scene(&mut scene);
+    let mut ray_tracing = false;
 
     while window.is_open() && !window.is_key_down(Key::Escape) {
         if window.is_key_down(Key::Left) {
@@ -35,16 +75,136 @@ fn main() {
         } else if window.is_key_down(Key::Right) {
             scene.camera.position.x += 0.1;
         }
+        if window.is_key_down(Key::Up) {
+            scene.camera.position.z += 0.1;
+        } else if window.is_key_down(Key::Down) {
+            scene.camera.position.z -= 0.1;
+        }
+        if window.is_key_pressed(Key::Key1, KeyRepeat::No) {
+            scene = Scene::new(1, 1);
+            init_cube_scene(&mut scene);
+        } else if window.is_key_pressed(Key::Key2, KeyRepeat::No) {
+            scene = Scene::new(1, 1);
+            init_sphere_scene(&mut scene);
+        }
+        if window.is_key_pressed(Key::Y, KeyRepeat::No) {
+            ray_tracing = !ray_tracing;
+        }
+        if window.is_key_pressed(Key::W, KeyRepeat::No) {
+            scene.render_mode = match scene.render_mode {
+                RenderMode::Wireframe => RenderMode::Filled,
+                RenderMode::Filled => RenderMode::Wireframe,
+            };
+        }
+        if window.is_key_pressed(Key::P, KeyRepeat::No) {
+            scene.texture_mapping = match scene.texture_mapping {
+                TextureMapping::Affine => TextureMapping::PerspectiveCorrect,
+                TextureMapping::PerspectiveCorrect => TextureMapping::Affine,
+            };
+        }
+        if window.is_key_pressed(Key::F, KeyRepeat::No) {
+            scene.texture_filter = match scene.texture_filter {
+                TextureFilter::Nearest => TextureFilter::Bilinear,
+                TextureFilter::Bilinear => TextureFilter::Trilinear,
+                TextureFilter::Trilinear => TextureFilter::Nearest,
+            };
+        }
+        if window.is_key_pressed(Key::G, KeyRepeat::No) {
+            let mode = match scene.fog.map(|fog| fog.mode) {
+                None => Some(FogMode::Linear {
+                    start: 5.,
+                    end: 20.,
+                }),
+                Some(FogMode::Linear { .. }) => Some(FogMode::Exponential { density: 0.1 }),
+                Some(FogMode::Exponential { .. }) => {
+                    Some(FogMode::ExponentialSquared { density: 0.1 })
+                }
+                Some(FogMode::ExponentialSquared { .. }) => None,
+            };
+            scene.fog = mode.map(|mode| Fog {
+                mode,
+                color: 0x8090A0,
+            });
+        }
+        if window.is_key_pressed(Key::Tab, KeyRepeat::No) {
+            // Outlines each instance in turn, then none.
+            scene.selected = match scene.selected {
+                None => Some(0),
+                Some(i) => Some(i + 1).filter(|i| *i < scene.instances.len()),
+            };
+        }
+        if window.is_key_pressed(Key::D, KeyRepeat::No) {
+            scene.pipeline = match scene.pipeline {
+                Pipeline::Forward => Pipeline::Deferred,
+                Pipeline::Deferred => Pipeline::Forward,
+            };
+        }
+        if window.is_key_pressed(Key::S, KeyRepeat::No) {
+            scene.shading = match scene.shading {
+                ShadingMode::Unlit => ShadingMode::Flat,
+                ShadingMode::Flat => ShadingMode::Gouraud,
+                ShadingMode::Gouraud => ShadingMode::Phong,
+                ShadingMode::Phong => ShadingMode::Unlit,
+            };
+        }
+        if window.is_key_pressed(Key::H, KeyRepeat::No) {
+            scene.shadows.filter = match scene.shadows.filter {
+                ShadowFilter::Hard => ShadowFilter::Pcf(1),
+                ShadowFilter::Pcf(1) => ShadowFilter::Pcf(2),
+                ShadowFilter::Pcf(_) => ShadowFilter::Hard,
+            };
+        }
+        if window.is_key_pressed(Key::A, KeyRepeat::No) {
+            canvas.line_style.anti_aliased = !canvas.line_style.anti_aliased;
+        }
+        if window.is_key_pressed(Key::T, KeyRepeat::No) {
+            canvas.line_style.width = if canvas.line_style.width > 1. { 1. } else { 3. };
+        }
+        if window.is_key_pressed(Key::R, KeyRepeat::No) {
+            canvas.rasterizer = match canvas.rasterizer {
+                Rasterizer::Scanline => Rasterizer::EdgeFunction,
+                Rasterizer::EdgeFunction => Rasterizer::Scanline,
+            };
+        }
 
-        scene.render(&mut canvas);
+        if window.is_key_pressed(Key::M, KeyRepeat::No) {
+            antialiasing = match antialiasing {
+                Antialiasing::Off => Antialiasing::Supersampling(2),
+                Antialiasing::Supersampling(_) => Antialiasing::Multisampling(4),
+                Antialiasing::Multisampling(_) => Antialiasing::Off,
+            };
+            canvas = canvas_for(antialiasing, &canvas);
+        }
+
+        if ray_tracing {
+            scene.ray_trace(&mut canvas);
+        } else {
+            scene.render(&mut canvas);
+        }
+        canvas.resolve_into(&mut screen);
 
         window
-            .update_with_buffer(&canvas.data, WIDTH, HEIGHT)
+            .update_with_buffer(&screen.data, WIDTH, HEIGHT)
             .unwrap();
         sleep(Duration::from_millis(30));
     }
 }
 
+// A canvas to render the scene to with the given anti-aliasing, with the
+// same settings as `old` otherwise.
+fn canvas_for(antialiasing: Antialiasing, old: &Canvas) -> Canvas {
+    let mut canvas = match antialiasing {
+        Antialiasing::Supersampling(factor) => Canvas::new(WIDTH * factor, HEIGHT * factor),
+        _ => Canvas::new(WIDTH, HEIGHT),
+    };
+    if let Antialiasing::Multisampling(samples) = antialiasing {
+        canvas.set_samples(samples).unwrap();
+    }
+    canvas.rasterizer = old.rasterizer;
+    canvas.line_style = old.line_style;
+    canvas
+}
+
 #[allow(dead_code)]
 fn update_point_and_velocity(p: &mut Point2, v: &mut Point2) {
     let hw = WIDTH / 2;
@@ -62,10 +222,14 @@ fn update_point_and_velocity(p: &mut Point2, v: &mut Point2) {
     }
 }
 
-struct Color {
+#[derive(Debug, Clone, Copy)]
+pub struct Color {
     r: u8,
     g: u8,
     b: u8,
+    // Opacity, from 0 (transparent) to 255 (opaque). Only used for blending,
+    // since the pixels of a canvas are always opaque.
+    a: u8,
     h: f32,
 }
 
@@ -73,101 +237,701 @@ pub struct Canvas {
     pub data: Vec<u32>,
     pub width: usize,
     pub height: usize,
+    // Stores 1/z for each pixel; 0 means "infinitely far away".
+    pub depth_buffer: Vec<f32>,
+    // 8 bits per pixel, which drawing tests and updates according to
+    // `stencil`.
+    pub stencil_buffer: Vec<u8>,
+    // The part of the canvas whose pixels are stored in `data`, and the other
+    // buffers: the whole canvas, unless this is one of its tiles.
+    region: Rect,
+    // Pixels outside this rectangle are never touched.
+    clip_rect: Rect,
+    pub rasterizer: Rasterizer,
+    pub line_style: LineStyle,
+    // Used by `blend_pixel` and everything that draws translucent colors.
+    pub blend_mode: BlendMode,
+    pub stencil: StencilState,
+    // With multisampling, the color, 1/z and stencil value of each sample of
+    // each pixel, which `resolve` averages into `data`. Without it, drawing
+    // goes straight to `data` and the other buffers, and these are empty.
+    samples: usize,
+    sample_data: Vec<u32>,
+    sample_depth: Vec<f32>,
+    sample_stencil: Vec<u8>,
 }
 
 impl Canvas {
     fn new(width: usize, height: usize) -> Canvas {
-        Canvas {
+        let mut canvas = Canvas {
             data: vec![0; width * height],
             width,
             height,
+            depth_buffer: vec![0.; width * height],
+            stencil_buffer: vec![0; width * height],
+            region: Rect::new(0, 0, -1, -1),
+            clip_rect: Rect::new(0, 0, -1, -1),
+            rasterizer: Rasterizer::Scanline,
+            line_style: LineStyle::default(),
+            blend_mode: BlendMode::SourceOver,
+            stencil: StencilState::default(),
+            samples: 1,
+            sample_data: Vec::new(),
+            sample_depth: Vec::new(),
+            sample_stencil: Vec::new(),
+        };
+        canvas.region = canvas.bounds();
+        canvas.reset_clip_rect();
+        canvas
+    }
+
+    // The rectangle covering the whole canvas. Since the origin is in the
+    // center, it extends one pixel further to the left and to the top when
+    // the dimensions are even.
+    pub fn bounds(&self) -> Rect {
+        let hw = (self.width / 2) as i32;
+        let hh = (self.height / 2) as i32;
+        Rect::new(
+            -hw,
+            hh - self.height as i32 + 1,
+            self.width as i32 - hw - 1,
+            hh,
+        )
+    }
+
+    pub fn clip_rect(&self) -> Rect {
+        self.clip_rect
+    }
+
+    // Restricts all drawing to `rect`, or rather to the part of it that's
+    // inside the canvas (or tile).
+    pub fn set_clip_rect(&mut self, rect: Rect) {
+        self.clip_rect = rect.intersect(&self.region);
+    }
+
+    pub fn reset_clip_rect(&mut self) {
+        self.clip_rect = self.region;
+    }
+
+    // Splits the canvas into tiles of at most `size` by `size` pixels, row
+    // by row from the top left, each with a copy of its part of the buffers
+    // and of the settings. Tiles can be drawn on independently, e.g. from
+    // different threads, with the same coordinates as the whole canvas, then
+    // put back with `merge_tiles`.
+    pub fn split_tiles(&self, size: usize) -> Vec<Canvas> {
+        let size = size as i32;
+        let r = self.region;
+        let mut tiles = Vec::new();
+        for top in (r.bottom..r.top + 1).rev().step_by(size as usize) {
+            for left in (r.left..r.right + 1).step_by(size as usize) {
+                let region = Rect::new(left, top - size + 1, left + size - 1, top).intersect(&r);
+                let mut tile = Canvas {
+                    data: Vec::new(),
+                    depth_buffer: Vec::new(),
+                    stencil_buffer: Vec::new(),
+                    region,
+                    clip_rect: self.clip_rect.intersect(&region),
+                    sample_data: Vec::new(),
+                    sample_depth: Vec::new(),
+                    sample_stencil: Vec::new(),
+                    ..*self
+                };
+                tile.copy_region(self, region);
+                tiles.push(tile);
+            }
+        }
+        tiles
+    }
+
+    // Copies the pixels of the tiles back into the canvas.
+    pub fn merge_tiles(&mut self, tiles: Vec<Canvas>) {
+        for tile in tiles {
+            self.copy_region(&tile, tile.region);
+        }
+    }
+
+    // Copies the pixels of `rect` from `other`, which must contain it, to
+    // this canvas, allocating its buffers if they're empty.
+    fn copy_region(&mut self, other: &Canvas, rect: Rect) {
+        let (w, h) = (
+            (rect.right - rect.left + 1) as usize,
+            (rect.top - rect.bottom + 1) as usize,
+        );
+        let n = self.samples;
+        let len = self.region_len();
+        self.data.resize(len, 0);
+        self.depth_buffer.resize(len, 0.);
+        self.stencil_buffer.resize(len, 0);
+        if n > 1 {
+            self.sample_data.resize(len * n, 0);
+            self.sample_depth.resize(len * n, 0.);
+            self.sample_stencil.resize(len * n, 0);
+        }
+        for row in 0..h as i32 {
+            let (Some(dst), Some(src)) = (
+                self.region_index(rect.left, rect.top - row),
+                other.region_index(rect.left, rect.top - row),
+            ) else {
+                continue;
+            };
+            self.data[dst..dst + w].copy_from_slice(&other.data[src..src + w]);
+            self.depth_buffer[dst..dst + w].copy_from_slice(&other.depth_buffer[src..src + w]);
+            self.stencil_buffer[dst..dst + w].copy_from_slice(&other.stencil_buffer[src..src + w]);
+            if n > 1 {
+                self.sample_data[dst * n..(dst + w) * n]
+                    .copy_from_slice(&other.sample_data[src * n..(src + w) * n]);
+                self.sample_depth[dst * n..(dst + w) * n]
+                    .copy_from_slice(&other.sample_depth[src * n..(src + w) * n]);
+                self.sample_stencil[dst * n..(dst + w) * n]
+                    .copy_from_slice(&other.sample_stencil[src * n..(src + w) * n]);
+            }
         }
     }
 
+    // The number of pixels stored in `data`.
+    fn region_len(&self) -> usize {
+        let r = self.region;
+        ((r.right - r.left + 1).max(0) * (r.top - r.bottom + 1).max(0)) as usize
+    }
+
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    // Sets the number of samples per pixel for multisample anti-aliasing,
+    // which must be one of `SUPPORTED_SAMPLES` (1 turns it off), or else the
+    // canvas is left unchanged. Otherwise, the content of the canvas is lost.
+    pub fn set_samples(&mut self, samples: usize) -> Result<(), UnsupportedSamples> {
+        if !SUPPORTED_SAMPLES.contains(&samples) {
+            return Err(UnsupportedSamples(samples));
+        }
+        let len = if samples > 1 {
+            self.data.len() * samples
+        } else {
+            0
+        };
+        self.samples = samples;
+        self.sample_data = vec![0; len];
+        self.sample_depth = vec![0.; len];
+        self.sample_stencil = vec![0; len];
+        self.fill(0);
+        Ok(())
+    }
+
     pub fn fill(&mut self, color: u32) {
-        for b in self.data.iter_mut() {
+        for b in self.data.iter_mut().chain(self.sample_data.iter_mut()) {
             *b = color;
         }
+        for d in self
+            .depth_buffer
+            .iter_mut()
+            .chain(self.sample_depth.iter_mut())
+        {
+            *d = 0.;
+        }
+        self.clear_stencil(0);
+    }
+
+    pub fn clear_stencil(&mut self, value: u8) {
+        for s in self
+            .stencil_buffer
+            .iter_mut()
+            .chain(self.sample_stencil.iter_mut())
+        {
+            *s = value;
+        }
+    }
+
+    // The buffers that drawing goes to, with one entry per sample.
+    fn sample_buffers(&mut self) -> (&mut [u32], &mut [f32], &mut [u8]) {
+        if self.samples > 1 {
+            (
+                &mut self.sample_data,
+                &mut self.sample_depth,
+                &mut self.sample_stencil,
+            )
+        } else {
+            (
+                &mut self.data,
+                &mut self.depth_buffer,
+                &mut self.stencil_buffer,
+            )
+        }
+    }
+
+    // Runs the stencil test for the samples of pixel `i` in `mask`, updates
+    // the stencil of those that fail it, and returns the mask of the others.
+    fn stencil_test(&mut self, i: usize, mask: u32) -> u32 {
+        let (n, state) = (self.samples, self.stencil);
+        if !state.is_enabled() {
+            return mask;
+        }
+        let (_, _, stencil) = self.sample_buffers();
+        let mut passed = 0;
+        for s in (0..n).filter(|s| mask & (1 << s) != 0) {
+            let j = i * n + s;
+            if state.test(stencil[j]) {
+                passed |= 1 << s;
+            } else {
+                stencil[j] = state.apply(state.fail, stencil[j]);
+            }
+        }
+        passed
+    }
+
+    // Updates the stencil of the samples of pixel `i` in `mask` with `op`.
+    fn stencil_update(&mut self, i: usize, mask: u32, op: StencilOp) {
+        let (n, state) = (self.samples, self.stencil);
+        if op == StencilOp::Keep {
+            return;
+        }
+        let (_, _, stencil) = self.sample_buffers();
+        for j in sample_indices(n, i, mask) {
+            stencil[j] = state.apply(op, stencil[j]);
+        }
+    }
+
+    // The index of the pixel in `data`, or None if it's outside the clip rect.
+    fn index(&self, x: i32, y: i32) -> Option<usize> {
+        if !self.clip_rect.contains(x, y) {
+            return None;
+        }
+        self.region_index(x, y)
+    }
+
+    // Same as `index`, but ignoring the clip rect.
+    fn region_index(&self, x: i32, y: i32) -> Option<usize> {
+        let r = self.region;
+        if !r.contains(x, y) {
+            return None;
+        }
+        let x_norm = x - r.left;
+        let y_norm = r.top - y;
+        Some(y_norm as usize * (r.right - r.left + 1) as usize + x_norm as usize)
+    }
+
+    // Moves the point to the nearest pixel that's inside the canvas.
+    pub fn clamp(&self, p: Point2) -> Point2 {
+        let bounds = self.bounds();
+        Point2::new(
+            p.x.clamp(bounds.left, bounds.right),
+            p.y.clamp(bounds.bottom, bounds.top),
+        )
     }
 
     pub fn set_pixel(&mut self, x: i32, y: i32, color: u32) {
-        let hw = self.width / 2;
-        let hh = self.height / 2;
-        let x_norm = x + hw as i32;
-        let y_norm = hh as i32 - y;
-        self.data[y_norm as usize * self.width + x_norm as usize] = color;
+        self.set_pixel_masked(x, y, u32::MAX, color);
+    }
+
+    // Only sets the samples of the pixel that are in `mask` (see
+    // `sample_pattern`).
+    pub fn set_pixel_masked(&mut self, x: i32, y: i32, mask: u32, color: u32) {
+        if let Some(i) = self.index(x, y) {
+            let mask = self.stencil_test(i, mask);
+            let n = self.samples;
+            let (data, _, _) = self.sample_buffers();
+            for j in sample_indices(n, i, mask) {
+                data[j] = color;
+            }
+            self.stencil_update(i, mask, self.stencil.pass);
+        }
+    }
+
+    // Combines `color` with the pixel according to `blend_mode`, with its
+    // alpha scaled by `coverage` (between 0 and 1).
+    pub fn blend_pixel(&mut self, x: i32, y: i32, color: Color, coverage: f32) {
+        self.blend_pixel_masked(x, y, u32::MAX, color, coverage);
+    }
+
+    // Like `blend_pixel`, but only for the samples of the pixel in `mask`.
+    pub fn blend_pixel_masked(&mut self, x: i32, y: i32, mask: u32, color: Color, coverage: f32) {
+        if let Some(i) = self.index(x, y) {
+            let mask = self.stencil_test(i, mask);
+            let (n, mode) = (self.samples, self.blend_mode);
+            let (data, _, _) = self.sample_buffers();
+            for j in sample_indices(n, i, mask) {
+                data[j] = mode.blend(color, Color::from(data[j]), coverage).into();
+            }
+            self.stencil_update(i, mask, self.stencil.pass);
+        }
+    }
+
+    // Like `blend_pixel_masked`, but only blends the samples in `mask` that
+    // are closer to the camera than what has already been drawn there, which
+    // are left at the depth they were, so that what's behind a translucent
+    // surface can still be drawn. Returns true if any sample was blended.
+    pub fn blend_pixel_masked_with_depth(
+        &mut self,
+        x: i32,
+        y: i32,
+        mask: u32,
+        inv_z: f32,
+        color: Color,
+        coverage: f32,
+    ) -> bool {
+        let Some(i) = self.index(x, y) else {
+            return false;
+        };
+        let mask = self.stencil_test(i, mask);
+        let (n, mode) = (self.samples, self.blend_mode);
+        let (data, depth, _) = self.sample_buffers();
+        let mut passed = 0;
+        for s in (0..n).filter(|s| mask & (1 << s) != 0) {
+            let j = i * n + s;
+            if inv_z > depth[j] {
+                data[j] = mode.blend(color, Color::from(data[j]), coverage).into();
+                passed |= 1 << s;
+            }
+        }
+        self.stencil_update(i, passed, self.stencil.pass);
+        self.stencil_update(i, mask & !passed, self.stencil.depth_fail);
+        passed != 0
+    }
+
+    // Blends `color` over every pixel of `rect`, e.g. for a translucent
+    // overlay.
+    pub fn fill_rect(&mut self, rect: Rect, color: Color) {
+        let rect = rect.intersect(&self.clip_rect);
+        for y in rect.bottom..rect.top + 1 {
+            for x in rect.left..rect.right + 1 {
+                self.blend_pixel(x, y, color, 1.);
+            }
+        }
+    }
+
+    // The 1/z stored for the pixel (0 if nothing was drawn there), or None if
+    // it's outside the canvas (or tile). With multisampling, this is only
+    // up to date after `resolve_into`.
+    pub fn depth_at(&self, x: i32, y: i32) -> Option<f32> {
+        self.region_index(x, y).map(|i| self.depth_buffer[i])
+    }
+
+    // Whether a pixel at depth 1/z = `inv_z` would be visible, i.e. inside
+    // the clip rect and in front of what has already been drawn there (in
+    // at least one of its samples).
+    pub fn is_closer(&self, x: i32, y: i32, inv_z: f32) -> bool {
+        let depth = if self.samples > 1 {
+            &self.sample_depth
+        } else {
+            &self.depth_buffer
+        };
+        self.index(x, y)
+            .is_some_and(|i| sample_indices(self.samples, i, u32::MAX).any(|j| inv_z > depth[j]))
+    }
+
+    // Only sets the pixel if it's closer to the camera than what has already
+    // been drawn there. Returns true if the pixel was written.
+    pub fn set_pixel_with_depth(&mut self, x: i32, y: i32, inv_z: f32, color: u32) -> bool {
+        self.set_pixel_masked_with_depth(x, y, u32::MAX, inv_z, color)
+    }
+
+    // Like `set_pixel_with_depth`, but with the depth test done separately
+    // for each of the samples in `mask`. Returns true if any was written.
+    pub fn set_pixel_masked_with_depth(
+        &mut self,
+        x: i32,
+        y: i32,
+        mask: u32,
+        inv_z: f32,
+        color: u32,
+    ) -> bool {
+        let Some(i) = self.index(x, y) else {
+            return false;
+        };
+        let mask = self.stencil_test(i, mask);
+        let n = self.samples;
+        let (data, depth, _) = self.sample_buffers();
+        let mut passed = 0;
+        for s in (0..n).filter(|s| mask & (1 << s) != 0) {
+            let j = i * n + s;
+            if inv_z > depth[j] {
+                depth[j] = inv_z;
+                data[j] = color;
+                passed |= 1 << s;
+            }
+        }
+        self.stencil_update(i, passed, self.stencil.pass);
+        self.stencil_update(i, mask & !passed, self.stencil.depth_fail);
+        passed != 0
     }
 
+    // Updates the stencil of the samples in `mask` of a pixel that isn't
+    // drawn because it's hidden, as if it had failed the depth test.
+    pub fn reject_pixel_masked(&mut self, x: i32, y: i32, mask: u32) {
+        if let Some(i) = self.index(x, y) {
+            let mask = self.stencil_test(i, mask);
+            self.stencil_update(i, mask, self.stencil.depth_fail);
+        }
+    }
+
+    // The stencil value of the pixel, or None if it's outside the canvas (or
+    // tile). With multisampling, that of its first sample.
+    pub fn stencil_at(&self, x: i32, y: i32) -> Option<u8> {
+        self.region_index(x, y).map(|i| {
+            if self.samples > 1 {
+                self.sample_stencil[i * self.samples]
+            } else {
+                self.stencil_buffer[i]
+            }
+        })
+    }
+
+    // Calls `f` for every pixel of the triangle that's inside the clip rect,
+    // with the mask of the samples it covers (just 1 without multisampling)
+    // and the attributes interpolated at the center of the pixel.
+    pub fn rasterize<const N: usize>(
+        &mut self,
+        p0: &Point2,
+        p1: &Point2,
+        p2: &Point2,
+        attrs: [[f32; N]; 3],
+        mut f: impl FnMut(&mut Canvas, i32, i32, u32, [f32; N]),
+    ) {
+        let (rasterizer, clip) = (self.rasterizer, self.clip_rect);
+        if self.samples > 1 {
+            // `set_samples` only accepts numbers that have a pattern.
+            let pattern = sample_pattern(self.samples).unwrap();
+            multisample_fill(p0, p1, p2, attrs, clip, pattern, |x, y, mask, a| {
+                f(self, x, y, mask, a)
+            });
+        } else {
+            rasterizer.fill(p0, p1, p2, attrs, clip, |x, y, a| f(self, x, y, 1, a));
+        }
+    }
+
+    // Averages the samples of each pixel into `data` (and keeps the closest
+    // depth), then averages each block of pixels into `target`, which must
+    // be smaller by a whole factor. This is the last step before displaying
+    // a canvas that was rendered with anti-aliasing.
+    pub fn resolve_into(&mut self, target: &mut Canvas) {
+        if self.samples > 1 {
+            let n = self.samples;
+            for i in 0..self.data.len() {
+                let samples = &self.sample_data[i * n..(i + 1) * n];
+                self.data[i] = average(samples.iter().map(|c| Color::from(*c)));
+                self.depth_buffer[i] = self.sample_depth[i * n..(i + 1) * n]
+                    .iter()
+                    .fold(0., |a, b| b.max(a));
+            }
+        }
+
+        let factor = self.width / target.width;
+        assert!(factor > 0 && target.width * factor == self.width);
+        assert_eq!(target.height * factor, self.height);
+        for ty in 0..target.height {
+            for tx in 0..target.width {
+                let block = (0..factor * factor).map(|k| {
+                    let (x, y) = (tx * factor + k % factor, ty * factor + k / factor);
+                    Color::from(self.data[y * self.width + x])
+                });
+                target.data[ty * target.width + tx] = average(block);
+            }
+        }
+    }
+
+    // Draws the line according to `line_style`.
     #[allow(dead_code)]
     fn draw_line(&mut self, p0: &Point2, p1: &Point2, color: u32) {
-        println!("draw_line p0: {:?}, p1: {:?}", p0, p1);
-        if (p1.x - p0.x).abs() > (p1.y - p0.y).abs() {
-            let (p0, p1) = if p0.x > p1.x { (p1, p0) } else { (p0, p1) };
-            let ys = interpolate(p0.x, p0.y as f32, p1.x, p1.y as f32);
-            for x in p0.x..p1.x + 1 {
-                self.set_pixel(x, ys[(x - p0.x) as usize] as i32, color);
-            }
+        self.draw_line_shaded(p0, p1, &|_, _| color);
+    }
+
+    // Like `draw_line`, with the color at each point given by `color`.
+    fn draw_line_shaded(&mut self, p0: &Point2, p1: &Point2, color: &dyn Fn(f32, f32) -> u32) {
+        if self.line_style.width > 1. {
+            self.draw_polyline(&[*p0, *p1], false, color);
+        } else if self.line_style.anti_aliased {
+            self.draw_wu_line(p0, p1, color);
         } else {
-            let (p0, p1) = if p0.y > p1.y { (p1, p0) } else { (p0, p1) };
-            let xs = interpolate(p0.y, p0.x as f32, p1.y, p1.x as f32);
-            for y in p0.y..p1.y + 1 {
-                self.set_pixel(xs[(y - p0.y) as usize] as i32, y, color);
+            walk_line(p0, p1, self.clip_rect, |x, y| {
+                self.set_pixel(x as i32, y as i32, color(x, y))
+            });
+        }
+    }
+
+    // Xiaolin Wu's algorithm: each step along the line is shared between the
+    // two pixels closest to it, according to how close they are.
+    fn draw_wu_line(&mut self, p0: &Point2, p1: &Point2, color: &dyn Fn(f32, f32) -> u32) {
+        let clip = self.clip_rect;
+        let steep = (p1.x - p0.x).abs() <= (p1.y - p0.y).abs();
+        walk_line(p0, p1, clip, |x, y| {
+            let color = Color::from(color(x, y));
+            if steep {
+                let (x0, f) = (x.floor(), x - x.floor());
+                self.blend_pixel(x0 as i32, y as i32, color, 1. - f);
+                self.blend_pixel(x0 as i32 + 1, y as i32, color, f);
+            } else {
+                let (y0, f) = (y.floor(), y - y.floor());
+                self.blend_pixel(x as i32, y0 as i32, color, 1. - f);
+                self.blend_pixel(x as i32, y0 as i32 + 1, color, f);
+            }
+        });
+    }
+
+    // Draws a line of width `line_style.width` through `points`, also joining
+    // the last point to the first if `closed`. Each pixel is drawn once, with
+    // the coverage of the piece of the line closest to it.
+    fn draw_polyline(&mut self, points: &[Point2], closed: bool, color: &dyn Fn(f32, f32) -> u32) {
+        let style = self.line_style;
+        let shapes: Vec<(Shape, Rect)> = stroke(points, closed, &style)
+            .into_iter()
+            .map(|s| {
+                let bounds = s.bounds();
+                (s, bounds)
+            })
+            .collect();
+        let Some(bounds) = shapes.iter().map(|(_, b)| *b).reduce(|a, b| a.union(&b)) else {
+            return;
+        };
+        let bounds = bounds.intersect(&self.clip_rect);
+        for y in bounds.bottom..bounds.top + 1 {
+            for x in bounds.left..bounds.right + 1 {
+                let d = shapes
+                    .iter()
+                    .filter(|(_, b)| b.contains(x, y))
+                    .map(|(s, _)| s.distance(x as f32, y as f32))
+                    .fold(f32::MAX, f32::min);
+                let coverage = style.coverage(d);
+                if coverage > 0. {
+                    let color = Color::from(color(x as f32, y as f32));
+                    self.blend_pixel(x, y, color, coverage);
+                }
             }
         }
     }
 
     #[allow(dead_code)]
     fn draw_wireframe(&mut self, p0: &Point2, p1: &Point2, p2: &Point2, color: u32) {
-        self.draw_line(p0, p1, color);
-        self.draw_line(p1, p2, color);
-        self.draw_line(p2, p0, color);
+        self.draw_wireframe_shaded(p0, p1, p2, &|_, _| color);
+    }
+
+    // Like `draw_wireframe`, with the color at each point given by `color`.
+    fn draw_wireframe_shaded(
+        &mut self,
+        p0: &Point2,
+        p1: &Point2,
+        p2: &Point2,
+        color: &dyn Fn(f32, f32) -> u32,
+    ) {
+        if self.line_style.width > 1. {
+            self.draw_polyline(&[*p0, *p1, *p2], true, color);
+        } else {
+            self.draw_line_shaded(p0, p1, color);
+            self.draw_line_shaded(p1, p2, color);
+            self.draw_line_shaded(p2, p0, color);
+        }
     }
 
     #[allow(dead_code)]
     fn draw_filled_triangle(&mut self, p0: &Point2, p1: &Point2, p2: &Point2, color: u32) {
-        let mut p0 = p0;
-        let mut p1 = p1;
-        let mut p2 = p2;
+        self.rasterize(p0, p1, p2, [[]; 3], |canvas, x, y, mask, _| {
+            canvas.set_pixel_masked(x, y, mask, color)
+        });
+    }
 
-        // Sort the points so that y0 <= y1 <= y2
-        if p1.y < p0.y {
-            std::mem::swap(&mut p1, &mut p0);
-        }
-        if p2.y < p0.y {
-            std::mem::swap(&mut p2, &mut p0);
-        }
-        if p2.y < p1.y {
-            std::mem::swap(&mut p2, &mut p1);
-        }
+    // From Listing 8-1. `h` holds the intensity at each of the three vertices,
+    // which is interpolated across the triangle and used to scale `color`.
+    #[allow(dead_code)]
+    fn draw_shaded_triangle(
+        &mut self,
+        p0: &Point2,
+        p1: &Point2,
+        p2: &Point2,
+        h: [f32; 3],
+        color: Color,
+    ) {
+        self.rasterize(p0, p1, p2, h.map(|h| [h]), |canvas, x, y, mask, [h]| {
+            let mut shaded = color;
+            shaded.mul(h);
+            canvas.set_pixel_masked(x, y, mask, shaded.into());
+        });
+    }
 
-        // Compute the x coordinates of the triangle edges
-        let mut x01 = interpolate(p0.y, p0.x as f32, p1.y, p1.x as f32);
-        let x12 = interpolate(p1.y, p1.x as f32, p2.y, p2.x as f32);
-        let x02 = interpolate(p0.y, p0.x as f32, p2.y, p2.x as f32);
+    // From Chapter 12. `inv_z` holds 1/z for each of the three vertices.
+    pub fn draw_filled_triangle_with_depth(
+        &mut self,
+        p0: &Point2,
+        p1: &Point2,
+        p2: &Point2,
+        inv_z: [f32; 3],
+        color: u32,
+    ) {
+        self.rasterize(
+            p0,
+            p1,
+            p2,
+            inv_z.map(|iz| [iz]),
+            |canvas, x, y, mask, [iz]| {
+                canvas.set_pixel_masked_with_depth(x, y, mask, iz, color);
+            },
+        );
+    }
+}
 
-        // Concatenate the short sides
-        x01.pop();
-        let x012 = [x01, x12].concat();
+// The indices in the sample buffers of the samples of pixel `i` that are in
+// `mask`, with `samples` samples per pixel.
+fn sample_indices(samples: usize, i: usize, mask: u32) -> impl Iterator<Item = usize> {
+    (0..samples)
+        .filter(move |s| mask & (1 << s) != 0)
+        .map(move |s| i * samples + s)
+}
 
-        // Determine which is left and which is right
-        let m = x012.len() / 2;
-        let mut x_left = x02;
-        let mut x_right = x012;
-        if x_right[m] < x_left[m] {
-            std::mem::swap(&mut x_left, &mut x_right);
-        }
+// The average of the colors, rounded.
+fn average(colors: impl Iterator<Item = Color>) -> u32 {
+    let (mut r, mut g, mut b, mut n) = (0, 0, 0, 0);
+    for c in colors {
+        (r, g, b, n) = (r + c.r as u32, g + c.g as u32, b + c.b as u32, n + 1);
+    }
+    let avg = |sum: u32| ((sum + n / 2) / n) as u8;
+    Color::rgb(avg(r), avg(g), avg(b)).into()
+}
 
-        // Draw the horizontal segments
-        for y in p0.y..p2.y + 1 {
-            let x_start = x_left[(y - p0.y) as usize] as i32;
-            let x_end = x_right[(y - p0.y) as usize] as i32;
-            for x in x_start..x_end + 1 {
-                self.set_pixel(x, y, color);
-            }
+// Calls `f` with one point per pixel along the major axis of the line from
+// p0 to p1, for the part of it that's within a pixel of `clip`, since that's
+// how far from the line the pixels that are drawn can be. The coordinate
+// along the minor axis is not rounded.
+fn walk_line(p0: &Point2, p1: &Point2, clip: Rect, mut f: impl FnMut(f32, f32)) {
+    let clip = Rect::new(clip.left - 1, clip.bottom - 1, clip.right + 1, clip.top + 1);
+    let Some((t0, t1)) = clip.clip_segment(p0, p1) else {
+        return;
+    };
+    if (p1.x - p0.x).abs() > (p1.y - p0.y).abs() {
+        let (p0, p1, t0, t1) = if p0.x > p1.x {
+            (p1, p0, 1. - t1, 1. - t0)
+        } else {
+            (p0, p1, t0, t1)
+        };
+        let (x0, x1) = clipped_range(p0.x, p1.x, t0, t1);
+        let mut ys = Interpolator::new(p0.x, p0.y as f32, p1.x, p1.y as f32);
+        ys.advance(x0 - p0.x);
+        for (x, y) in (x0..x1 + 1).zip(ys) {
+            f(x as f32, y);
+        }
+    } else {
+        let (p0, p1, t0, t1) = if p0.y > p1.y {
+            (p1, p0, 1. - t1, 1. - t0)
+        } else {
+            (p0, p1, t0, t1)
+        };
+        let (y0, y1) = clipped_range(p0.y, p1.y, t0, t1);
+        let mut xs = Interpolator::new(p0.y, p0.x as f32, p1.y, p1.x as f32);
+        xs.advance(y0 - p0.y);
+        for (y, x) in (y0..y1 + 1).zip(xs) {
+            f(x, y as f32);
         }
     }
 }
 
+// The integer values between i0 and i1 (with i0 <= i1) that correspond to
+// the parameter range [t0, t1]. Rounding errors in t are tolerated, since any
+// resulting pixels outside the clip rect are discarded anyway.
+fn clipped_range(i0: i32, i1: i32, t0: f32, t1: f32) -> (i32, i32) {
+    let di = (i1 - i0) as f32;
+    (
+        i0 + (t0 * di - 0.001).ceil() as i32,
+        i0 + (t1 * di + 0.001).floor() as i32,
+    )
+}
+
 impl fmt::Display for Canvas {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut s = String::new();
@@ -192,22 +956,123 @@ impl fmt::Display for Canvas {
 }
 
 pub fn init_cube_scene(scene: &mut Scene) {
-    let cube = Rc::new(Model::cube());
+    let mut cube = Model::cube();
+    cube.texture = Some(Arc::new(Texture::checkerboard(
+        64,
+        8,
+        Color::rgb(230, 230, 230).into(),
+        Color::rgb(160, 40, 40).into(),
+    )));
+    // Grooves between the squares.
+    let heights: Vec<f32> = (0..64 * 64)
+        .map(|i| {
+            if i % 64 % 8 == 0 || i / 64 % 8 == 0 {
+                0.
+            } else {
+                1.
+            }
+        })
+        .collect();
+    cube.normal_map = Some(Arc::new(Texture::normal_map(64, 64, &heights, 2.)));
+    cube.specular = Some(50.);
+    let cube = Arc::new(cube);
+
+    // A floor for the first cube to stand on, and the cubes to cast their
+    // shadows on.
+    let mut floor = Model::new(
+        vec![
+            Point3::new(-1., 0., -1.),
+            Point3::new(1., 0., -1.),
+            Point3::new(1., 0., 1.),
+            Point3::new(-1., 0., 1.),
+        ],
+        vec![
+            Triangle::new((0, 2, 1), Color::rgb(180, 180, 180)).with_uvs([
+                (0., 1.),
+                (1., 0.),
+                (1., 1.),
+            ]),
+            Triangle::new((0, 3, 2), Color::rgb(180, 180, 180)).with_uvs([
+                (0., 1.),
+                (0., 0.),
+                (1., 0.),
+            ]),
+        ],
+    );
+    // Fine enough to shimmer in the distance without mipmapping.
+    floor.texture = Some(Arc::new(Texture::checkerboard(
+        256,
+        32,
+        Color::rgb(180, 180, 180).into(),
+        Color::rgb(120, 120, 120).into(),
+    )));
+
+    // From Chapter 3, except for the point light, which is above the cubes
+    // here so that their shadows fall on the floor.
+    scene.lights = vec![
+        Light::ambient(0.2),
+        Light::directional(0.2, Point3::new(-1., 0., 1.)),
+        Light::point(0.6, Point3::new(-4., 5., 0.)).with_shadows(),
+    ];
 
     let obj1 = Instance::new(
-        Rc::clone(&cube),
+        Arc::clone(&cube),
         Point3::new(-1.5, 0., 7.),
         Matrix4::identity(),
         0.75,
     );
     let obj2 = Instance::new(
-        Rc::clone(&cube),
+        Arc::clone(&cube),
         Point3::new(1.25, 2.5, 7.5),
         Matrix4::from_rotation_y(195. * PI / 2.),
         1.0,
     );
     scene.instances.push(obj1);
     scene.instances.push(obj2);
+    scene.instances.push(Instance::new(
+        Arc::new(floor),
+        Point3::new(0., -0.75, 8.),
+        Matrix4::identity(),
+        6.,
+    ));
+}
+
+// From Chapter 5, seen from the origin, plus a glass ball.
+pub fn init_sphere_scene(scene: &mut Scene) {
+    scene.camera = Camera {
+        position: Point3::new(0., 0., 0.),
+        orientation: Matrix4::identity(),
+    };
+    let sphere = |center, radius, color, specular, reflective| {
+        Sphere::new(center, radius, color)
+            .with_specular(specular)
+            .with_reflective(reflective)
+    };
+    scene.spheres = vec![
+        sphere(Point3::new(0., -1., 3.), 1., Color::red(), 500., 0.2),
+        sphere(Point3::new(2., 0., 4.), 1., Color::blue(), 500., 0.3),
+        sphere(Point3::new(-2., 0., 4.), 1., Color::green(), 10., 0.4),
+        sphere(
+            Point3::new(0., -5001., 0.),
+            5000.,
+            Color::yellow(),
+            1000.,
+            0.5,
+        ),
+        sphere(
+            Point3::new(-0.45, -0.65, 1.7),
+            0.35,
+            Color::rgb(255, 255, 255),
+            500.,
+            0.,
+        )
+        .with_transparency(0.9, 1.5),
+    ];
+    scene.lights = vec![
+        Light::ambient(0.2),
+        Light::point(0.6, Point3::new(2., 1., 0.)).with_shadows(),
+        Light::directional(0.2, Point3::new(1., 4., 4.)).with_shadows(),
+    ];
 }
 
 #[cfg(test)]
@@ -216,7 +1081,7 @@ mod test {
 
     fn canvas_with_filled_triangle(p1: &Point2, p2: &Point2, p3: &Point2) -> Canvas {
         let mut canvas = Canvas::new(3, 3);
-        canvas.draw_filled_triangle(&p1, &p2, &p3, 0xFFFFFF);
+        canvas.draw_filled_triangle(p1, p2, p3, 0xFFFFFF);
         canvas
     }
 
@@ -237,6 +1102,488 @@ X - - - -
         );
     }
 
+    #[test]
+    fn test_draw_line_clipped() {
+        let mut canvas = Canvas::new(5, 5);
+        canvas.draw_line(&Point2::new(-1000, 0), &Point2::new(1000, 0), 0xFFFFFF);
+        canvas.draw_line(&Point2::new(-4, -4), &Point2::new(4, 4), 0xFFFFFF);
+        canvas.draw_line(&Point2::new(3, -10), &Point2::new(10, 3), 0xFFFFFF);
+        assert_eq!(
+            canvas.to_string(),
+            "
+- - - - X
+- - - X -
+X X X X X
+- X - - -
+X - - - -
+        "
+            .trim()
+        );
+    }
+
+    #[test]
+    fn test_draw_line_anti_aliased() {
+        let mut canvas = Canvas::new(5, 3);
+        canvas.line_style.anti_aliased = true;
+        canvas.draw_line(&Point2::new(-2, -1), &Point2::new(2, 0), 0xFFFFFF);
+        // Each column is split between the two rows closest to the line.
+        let gray = |c: f32| {
+            let v = (255. * c).round() as u8;
+            u32::from(Color::rgb(v, v, v))
+        };
+        assert_eq!(
+            canvas.data,
+            vec![
+                0,
+                0,
+                0,
+                0,
+                0, //
+                0,
+                gray(0.25),
+                gray(0.5),
+                gray(0.75),
+                0xFFFFFF, //
+                0xFFFFFF,
+                gray(0.75),
+                gray(0.5),
+                gray(0.25),
+                0,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_draw_thick_line() {
+        let mut canvas = Canvas::new(7, 7);
+        canvas.line_style.width = 3.;
+        canvas.draw_line(&Point2::new(-1, 2), &Point2::new(1, 2), 0xFFFFFF);
+        canvas.line_style.width = 2.;
+        canvas.line_style.cap = LineCap::Square;
+        canvas.draw_line(&Point2::new(-1, -2), &Point2::new(1, -2), 0xFFFFFF);
+        assert_eq!(
+            canvas.to_string(),
+            "
+- - X X X - -
+- - X X X - -
+- - X X X - -
+- - - - - - -
+- X X X X X -
+- X X X X X -
+- X X X X X -
+        "
+            .trim()
+        );
+    }
+
+    #[test]
+    fn test_antialiasing() {
+        let gray = |v: u8| u32::from(Color::rgb(v, v, v));
+
+        // Pixels on the diagonal edge have half of their samples covered.
+        let mut canvas = Canvas::new(4, 4);
+        canvas.set_samples(4).unwrap();
+        canvas.draw_filled_triangle(
+            &Point2::new(-2, -1),
+            &Point2::new(1, -1),
+            &Point2::new(1, 2),
+            0xFFFFFF,
+        );
+        let mut screen = Canvas::new(4, 4);
+        canvas.resolve_into(&mut screen);
+        let pixel = |x: i32, y: i32| screen.data[((2 - y) * 4 + x + 2) as usize];
+        assert_eq!(pixel(-1, 0), gray(128));
+        assert_eq!(pixel(0, 1), gray(128));
+        assert_eq!(pixel(0, 0), gray(255));
+        assert_eq!(pixel(-1, 1), 0);
+
+        // Numbers of samples without a pattern are rejected.
+        for samples in [0, 3, 6, 16] {
+            assert_eq!(
+                canvas.set_samples(samples),
+                Err(UnsupportedSamples(samples))
+            );
+            assert_eq!(canvas.samples(), 4);
+        }
+
+        // Supersampling averages blocks of pixels.
+        let mut canvas = Canvas::new(4, 2);
+        canvas.set_pixel(-2, 1, 0xFFFFFF);
+        canvas.set_pixel(0, 1, 0xFFFFFF);
+        canvas.set_pixel(1, 0, 0xFFFFFF);
+        let mut screen = Canvas::new(2, 1);
+        canvas.resolve_into(&mut screen);
+        assert_eq!(screen.data, vec![gray(64), gray(128)]);
+    }
+
+    #[test]
+    fn test_fill_rect_blended() {
+        let mut canvas = Canvas::new(3, 1);
+        canvas.fill(0x204060);
+        canvas.fill_rect(Rect::new(-1, 0, 0, 0), Color::rgba(255, 255, 255, 128));
+        canvas.blend_mode = BlendMode::Additive;
+        canvas.fill_rect(Rect::new(0, 0, 5, 0), Color::rgb(0, 100, 0));
+        assert_eq!(canvas.data, vec![0x90A0B0, 0x90FFB0, 0x20A460]);
+    }
+
+    #[test]
+    fn test_translucent_behind_opaque() {
+        // A red triangle in front of a larger translucent blue square.
+        let mut scene = Scene::new(1, 1);
+        scene.camera.position = Point3::default();
+        scene.camera.orientation = Matrix4::identity();
+        scene.shading = ShadingMode::Unlit;
+        let triangle = Model::new(
+            vec![
+                Point3::new(-0.6, -0.6, 2.),
+                Point3::new(0.6, -0.6, 2.),
+                Point3::new(0., 0.6, 2.),
+            ],
+            vec![Triangle::new((0, 1, 2), Color::red())],
+        );
+        let mut glass = Model::new(
+            vec![
+                Point3::new(-1.6, -1.6, 4.),
+                Point3::new(1.6, -1.6, 4.),
+                Point3::new(1.6, 1.6, 4.),
+                Point3::new(-1.6, 1.6, 4.),
+            ],
+            vec![
+                Triangle::new((0, 1, 2), Color::blue()),
+                Triangle::new((0, 2, 3), Color::blue()),
+            ],
+        );
+        glass.opacity = 0.5;
+        for mut model in [triangle, glass] {
+            model.cull_back_faces = false;
+            scene.instances.push(Instance::new(
+                Arc::new(model),
+                Point3::default(),
+                Matrix4::identity(),
+                1.,
+            ));
+        }
+        let mut canvas = Canvas::new(20, 20);
+        canvas.set_samples(4).unwrap();
+        scene.render(&mut canvas);
+
+        // The samples on the edges of the triangle are either red or blue,
+        // but the blue is never blended over the red.
+        let red = u32::from(Color::red());
+        let glass = u32::from(Color::rgb(0, 0, 128));
+        let mut edges = 0;
+        for pixel in canvas.sample_data.chunks(4) {
+            assert!(pixel.iter().all(|s| [0, red, glass].contains(s)));
+            if pixel.contains(&red) && pixel.contains(&glass) {
+                edges += 1;
+            }
+        }
+        assert!(edges > 0);
+    }
+
+    #[test]
+    fn test_render_tiled() {
+        type Configure = dyn Fn(&mut Scene, &mut Canvas);
+
+        // Odd sizes, so that the tiles on the edges are partial.
+        let render = |threads: usize, configure: &Configure| {
+            let mut canvas = Canvas::new(201, 149);
+            let mut scene = Scene::new(1, 1);
+            init_cube_scene(&mut scene);
+            let mut glass = Model::cube();
+            glass.opacity = 0.5;
+            scene.instances.push(Instance::new(
+                Arc::new(glass),
+                Point3::new(-1., 0.5, 5.),
+                Matrix4::identity(),
+                0.5,
+            ));
+            scene.threads = threads;
+            configure(&mut scene, &mut canvas);
+            scene.render(&mut canvas);
+            canvas.resolve_into(&mut Canvas::new(201, 149));
+            (canvas.data, canvas.depth_buffer)
+        };
+        let configurations: [&Configure; 7] = [
+            &|_, _| {},
+            &|scene, _| scene.camera.position = Point3::new(-1., 0.5, 3.5),
+            &|scene, canvas| {
+                scene.shading = ShadingMode::Gouraud;
+                scene.texture_mapping = TextureMapping::Affine;
+                canvas.rasterizer = Rasterizer::EdgeFunction;
+            },
+            &|_, canvas| canvas.set_samples(4).unwrap(),
+            &|scene, _| scene.render_mode = RenderMode::Wireframe,
+            &|scene, canvas| {
+                scene.render_mode = RenderMode::Wireframe;
+                canvas.line_style.anti_aliased = true;
+                canvas.line_style.width = 3.;
+                scene.fog = Some(Fog {
+                    mode: FogMode::Exponential { density: 0.1 },
+                    color: 0x8090A0,
+                });
+            },
+            &|scene, canvas| {
+                // Triangles with 30° corners pointing at the first column of
+                // tiles to the right of the center, whose miters reach about
+                // twice as far past the corners as the sides of the lines.
+                scene.render_mode = RenderMode::Wireframe;
+                canvas.line_style.width = 3.;
+                scene.camera.position = Point3::default();
+                scene.camera.orientation = Matrix4::identity();
+                let mut vertices = Vec::new();
+                let mut triangles = Vec::new();
+                for (i, tip) in (20..26).enumerate() {
+                    let (x, y) = (tip as f32, 15. + 10. * i as f32);
+                    for (x, y) in [(x, y), (x - 15., y - 4.), (x - 15., y + 4.)] {
+                        // At z = 1, away from pixel edges.
+                        let (x, y) = ((x + 0.5) / 201., (y + 0.5) / 149.);
+                        vertices.push(Point3::new(x, y, 1.));
+                    }
+                    let i = 3 * i;
+                    triangles.push(Triangle::new((i, i + 1, i + 2), 0xFFFFFFu32));
+                }
+                let mut sharp = Model::new(vertices, triangles);
+                sharp.cull_back_faces = false;
+                scene.instances.push(Instance::new(
+                    Arc::new(sharp),
+                    Point3::default(),
+                    Matrix4::identity(),
+                    1.,
+                ));
+            },
+        ];
+        for configure in configurations {
+            assert!(render(1, configure) == render(4, configure));
+        }
+    }
+
+    #[test]
+    fn test_deferred() {
+        let render = |pipeline: Pipeline| {
+            let mut canvas = Canvas::new(101, 75);
+            let mut scene = Scene::new(1, 1);
+            init_cube_scene(&mut scene);
+            for light in &mut scene.lights {
+                light.casts_shadows = false;
+            }
+            scene.pipeline = pipeline;
+            scene.render(&mut canvas);
+            canvas.data
+        };
+        assert!(render(Pipeline::Forward) == render(Pipeline::Deferred));
+    }
+
+    #[test]
+    fn test_ray_trace() {
+        let mut canvas = Canvas::new(60, 60);
+        let mut scene = Scene::new(1, 1);
+        init_sphere_scene(&mut scene);
+        scene.shading = ShadingMode::Unlit;
+        scene.reflection_depth = 0;
+        scene.ray_trace(&mut canvas);
+        let pixel = |canvas: &Canvas, x, y| canvas.data[canvas.region_index(x, y).unwrap()];
+        assert_eq!(pixel(&canvas, 0, -10), Color::red().into());
+        assert_eq!(pixel(&canvas, 15, 0), Color::blue().into());
+        assert_eq!(pixel(&canvas, -15, 0), Color::green().into());
+        assert_eq!(pixel(&canvas, 0, 20), 0);
+
+        // The point light is up and to the right of the red sphere, so it's
+        // brighter on that side. Lighting doesn't change the hue.
+        scene.shading = ShadingMode::Phong;
+        scene.ray_trace(&mut canvas);
+        let rgb = |canvas: &Canvas, x, y| {
+            let c = Color::from(pixel(canvas, x, y));
+            (c.r, c.g, c.b)
+        };
+        assert!(rgb(&canvas, 5, -5).0 > rgb(&canvas, -5, -15).0);
+        assert_eq!((rgb(&canvas, 5, -5).1, rgb(&canvas, 5, -5).2), (0, 0));
+
+        // The bottom of the red sphere reflects the yellow floor.
+        scene.reflection_depth = 3;
+        scene.ray_trace(&mut canvas);
+        assert!(rgb(&canvas, 0, -27).1 > 0);
+
+        // Shadows only ever darken.
+        let shadowed = canvas.data.clone();
+        for light in &mut scene.lights {
+            light.casts_shadows = false;
+        }
+        scene.ray_trace(&mut canvas);
+        assert!(shadowed != canvas.data);
+        for (a, b) in shadowed.iter().zip(&canvas.data) {
+            let (a, b) = (Color::from(*a), Color::from(*b));
+            assert!(a.r <= b.r && a.g <= b.g && a.b <= b.b);
+        }
+    }
+
+    #[test]
+    fn test_ray_trace_refraction() {
+        let render = |glass: Option<f32>| {
+            let mut canvas = Canvas::new(60, 60);
+            let mut scene = Scene::new(1, 1);
+            init_sphere_scene(&mut scene);
+            scene.spheres.retain(|s| s.transparency == 0.);
+            if let Some(refractive_index) = glass {
+                scene.spheres.push(
+                    Sphere::new(Point3::new(0., 0., 2.), 0.5, Color::rgb(255, 255, 255))
+                        .with_transparency(1., refractive_index),
+                );
+            }
+            // Rays through the glass have fewer reflections left.
+            scene.reflection_depth = 10;
+            scene.ray_trace(&mut canvas);
+            canvas.data
+        };
+        // Perfectly clear glass that doesn't bend light is invisible, and
+        // doesn't cast shadows.
+        assert!(render(Some(1.)) == render(None));
+        assert!(render(Some(1.5)) != render(None));
+    }
+
+    #[test]
+    fn test_ray_trace_instances() {
+        // Both techniques see the same objects, in the same places.
+        let mut scene = Scene::new(1, 1);
+        init_cube_scene(&mut scene);
+        let mut rasterized = Canvas::new(101, 75);
+        scene.render(&mut rasterized);
+        let mut ray_traced = Canvas::new(101, 75);
+        scene.ray_trace(&mut ray_traced);
+        let mismatches = (rasterized.data.iter().zip(&ray_traced.data))
+            .filter(|(a, b)| (**a == 0) != (**b == 0))
+            .count();
+        assert!(rasterized.data.contains(&0));
+        // They can disagree about the pixels on the edges, since the
+        // rasterizer moves vertices to whole pixels.
+        assert!(mismatches < rasterized.data.len() / 30);
+
+        // Models can be mirrors or glass like spheres, which only the ray
+        // tracer shows.
+        let render = |configure: fn(&mut Model)| {
+            let mut scene = Scene::new(1, 1);
+            init_cube_scene(&mut scene);
+            let mut cube = Model::cube();
+            configure(&mut cube);
+            scene.instances.push(Instance::new(
+                Arc::new(cube),
+                Point3::new(-1., 0.5, 5.),
+                Matrix4::identity(),
+                0.5,
+            ));
+            let mut rasterized = Canvas::new(101, 75);
+            scene.render(&mut rasterized);
+            let mut ray_traced = Canvas::new(101, 75);
+            scene.ray_trace(&mut ray_traced);
+            (rasterized.data, ray_traced.data)
+        };
+        let (rasterized, ray_traced) = render(|_| {});
+        let (mirror_rasterized, mirror) = render(|cube| cube.reflective = 0.8);
+        assert!(mirror_rasterized == rasterized && mirror != ray_traced);
+        let (clear_rasterized, clear) = render(|cube| cube.opacity = 0.);
+        let (glass_rasterized, glass) = render(|cube| {
+            cube.opacity = 0.;
+            cube.refractive_index = 1.5;
+        });
+        assert!(glass_rasterized == clear_rasterized && glass != clear);
+    }
+
+    #[test]
+    fn test_clip_rect() {
+        let mut canvas = Canvas::new(5, 5);
+        canvas.set_clip_rect(Rect::new(-1, -1, 1, 3));
+        assert_eq!(canvas.clip_rect(), Rect::new(-1, -1, 1, 2));
+        canvas.draw_filled_triangle(
+            &Point2::new(-10, 10),
+            &Point2::new(10, 10),
+            &Point2::new(0, -10),
+            0xFFFFFF,
+        );
+        canvas.draw_line(&Point2::new(-2, -2), &Point2::new(2, -2), 0xFFFFFF);
+        assert_eq!(
+            canvas.to_string(),
+            "
+- X X X -
+- X X X -
+- X X X -
+- X X X -
+- - - - -
+        "
+            .trim()
+        );
+
+        canvas.reset_clip_rect();
+        canvas.draw_line(&Point2::new(-2, -2), &Point2::new(2, -2), 0xFFFFFF);
+        assert_eq!(canvas.to_string().lines().last(), Some("X X X X X"));
+    }
+
+    #[test]
+    fn test_stencil() {
+        let mut canvas = Canvas::new(3, 3);
+        canvas.stencil = StencilState::write(1);
+        canvas.draw_filled_triangle(
+            &Point2::new(-1, 1),
+            &Point2::new(0, 0),
+            &Point2::new(-1, -1),
+            0,
+        );
+        canvas.stencil = StencilState::only_where(false, 1, 1);
+        canvas.draw_filled_triangle(
+            &Point2::new(-10, 10),
+            &Point2::new(10, 10),
+            &Point2::new(0, -10),
+            0xFFFFFF,
+        );
+        assert_eq!(
+            canvas.to_string(),
+            "
+- X X
+- - X
+- X X
+        "
+            .trim()
+        );
+
+        // Hidden pixels update the stencil too.
+        canvas.stencil = StencilState {
+            reference: 5,
+            depth_fail: StencilOp::Increment,
+            pass: StencilOp::Replace,
+            ..Default::default()
+        };
+        assert!(canvas.set_pixel_with_depth(1, 1, 0.5, 0));
+        assert!(!canvas.set_pixel_with_depth(1, 1, 0.25, 0));
+        assert_eq!(canvas.stencil_at(1, 1), Some(6));
+    }
+
+    #[test]
+    fn test_outline_and_masking() {
+        let render = |f: &dyn Fn(&mut Scene)| {
+            let mut canvas = Canvas::new(101, 75);
+            let mut scene = Scene::new(1, 1);
+            init_cube_scene(&mut scene);
+            for light in &mut scene.lights {
+                light.casts_shadows = false;
+            }
+            f(&mut scene);
+            scene.render(&mut canvas);
+            (canvas.data, scene.outline_color)
+        };
+        let outlined =
+            |(data, color): &(Vec<u32>, u32)| data.iter().filter(|c| *c == color).count();
+        assert_eq!(outlined(&render(&|_| {})), 0);
+        assert!(outlined(&render(&|scene| scene.selected = Some(0))) > 0);
+
+        // Nothing marks the stencil buffer, so a masked instance isn't drawn.
+        let masked = render(&|scene| {
+            scene.instances[0].stencil = StencilState::only_where(true, 1, 1);
+        });
+        let removed = render(&|scene| {
+            scene.instances.remove(0);
+        });
+        assert!(masked.0 == removed.0);
+    }
+
     #[test]
     fn test_draw_filled_triangle() {
         let canvas = canvas_with_filled_triangle(
@@ -293,6 +1640,127 @@ X - -
         );
     }
 
+    #[test]
+    fn test_draw_filled_triangle_with_depth() {
+        let mut canvas = Canvas::new(3, 3);
+        let p1 = Point2::new(-1, 1);
+        let p2 = Point2::new(1, 1);
+        let p3 = Point2::new(-1, -1);
+        let p4 = Point2::new(1, -1);
+
+        // The far triangle is drawn last, but mustn't overwrite the near one.
+        canvas.draw_filled_triangle_with_depth(&p1, &p2, &p3, [0.5, 0.5, 0.5], 1);
+        canvas.draw_filled_triangle_with_depth(&p2, &p3, &p4, [0.25, 0.25, 0.25], 2);
+        assert_eq!(canvas.data, vec![1, 1, 1, 1, 1, 2, 1, 2, 2]);
+
+        // Clearing the canvas also resets the depth buffer.
+        canvas.fill(0);
+        canvas.draw_filled_triangle_with_depth(&p2, &p3, &p4, [0.25, 0.25, 0.25], 2);
+        assert_eq!(canvas.data, vec![0, 0, 2, 0, 2, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_draw_shaded_triangle() {
+        let mut canvas = Canvas::new(3, 3);
+        canvas.draw_shaded_triangle(
+            &Point2::new(-1, 1),
+            &Point2::new(1, 1),
+            &Point2::new(-1, -1),
+            [1., 0.5, 0.],
+            Color::red(),
+        );
+        assert_eq!(
+            canvas.data,
+            vec![0xFF0000, 0xBF0000, 0x7F0000, 0x7F0000, 0x3F0000, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_perspective_correct_texture_mapping() {
+        // A floor going from z = 2 to z = 4, i.e. from row -24 to row -12,
+        // with u going from 0 to 1 along it. Texel i has a blue value of 16i.
+        let render = |texture_mapping: TextureMapping| {
+            let vertices = vec![
+                Point3::new(-0.5, -0.5, 2.),
+                Point3::new(0.5, -0.5, 2.),
+                Point3::new(0.5, -0.5, 4.),
+                Point3::new(-0.5, -0.5, 4.),
+            ];
+            let triangles = vec![
+                Triangle::new((0, 1, 2), 0u32).with_uvs([(0., 0.), (0., 0.), (1., 0.)]),
+                Triangle::new((0, 2, 3), 0u32).with_uvs([(0., 0.), (1., 0.), (1., 0.)]),
+            ];
+            let mut floor = Model::new(vertices, triangles);
+            floor.cull_back_faces = false;
+            floor.texture = Some(Arc::new(Texture::new(
+                16,
+                1,
+                (0..16).map(|i| i * 16).collect(),
+            )));
+            let mut scene = Scene::new(1, 1);
+            scene.camera.position = Point3::default();
+            scene.camera.orientation = Matrix4::identity();
+            scene.shading = ShadingMode::Unlit;
+            scene.texture_filter = TextureFilter::Nearest;
+            scene.texture_mapping = texture_mapping;
+            scene.instances.push(Instance::new(
+                Arc::new(floor),
+                Point3::default(),
+                Matrix4::identity(),
+                1.,
+            ));
+            let mut canvas = Canvas::new(96, 96);
+            scene.render(&mut canvas);
+            move |y| canvas.data[canvas.region_index(0, y).unwrap()] / 16
+        };
+        let perspective = render(TextureMapping::PerspectiveCorrect);
+        let affine = render(TextureMapping::Affine);
+
+        // Row -20 is at z = 48 / 20, so u = (2.4 - 2) / 2 = 0.2, which is in
+        // texel 3, while affine mapping has u = 1/3 a third of the way up.
+        // The far half of the floor takes up less of the screen than the near
+        // half, so affine mapping samples too far along it everywhere between.
+        assert_eq!(perspective(-20), 3);
+        assert_eq!(affine(-20), 5);
+        for y in -23..-12 {
+            assert!(affine(y) > perspective(y));
+        }
+    }
+
+    #[test]
+    fn test_edge_function_rasterizer() {
+        let mut canvas = Canvas::new(5, 5);
+        canvas.rasterizer = Rasterizer::EdgeFunction;
+        let (a, b) = (Point2::new(-2, -2), Point2::new(2, -2));
+        let (c, d) = (Point2::new(2, 2), Point2::new(-2, 2));
+        canvas.draw_filled_triangle(&a, &b, &c, 1);
+        canvas.draw_filled_triangle(&a, &c, &d, 2);
+        // The diagonal belongs to the second triangle only, and the right and
+        // bottom edges of the square are left for its neighbours.
+        #[rustfmt::skip]
+        assert_eq!(
+            canvas.data,
+            vec![
+                2, 2, 2, 2, 0,
+                2, 2, 2, 1, 0,
+                2, 2, 1, 1, 0,
+                2, 1, 1, 1, 0,
+                0, 0, 0, 0, 0,
+            ]
+        );
+
+        // Unlike the scanline rasterizer, flat triangles cover no pixels.
+        let mut canvas = Canvas::new(3, 3);
+        canvas.rasterizer = Rasterizer::EdgeFunction;
+        canvas.draw_filled_triangle(
+            &Point2::new(-1, 1),
+            &Point2::new(0, 1),
+            &Point2::new(1, 1),
+            0xFFFFFF,
+        );
+        assert_eq!(canvas.data, vec![0; 9]);
+    }
+
     #[test]
     #[should_panic]
     fn test_filled_triangle_corner_cases() {
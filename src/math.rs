@@ -3,7 +3,7 @@ use core::f32::consts::PI;
 
 use std::ops::*;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct Point2 {
     pub x: i32,
     pub y: i32,
@@ -16,6 +16,83 @@ impl Point2 {
     }
 }
 
+/// An axis-aligned rectangle, with inclusive bounds. It's empty if
+/// left > right or bottom > top.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+    pub left: i32,
+    pub bottom: i32,
+    pub right: i32,
+    pub top: i32,
+}
+
+impl Rect {
+    pub fn new(left: i32, bottom: i32, right: i32, top: i32) -> Rect {
+        Rect {
+            left,
+            bottom,
+            right,
+            top,
+        }
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        self.left <= x && x <= self.right && self.bottom <= y && y <= self.top
+    }
+
+    pub fn intersect(&self, other: &Rect) -> Rect {
+        Rect {
+            left: self.left.max(other.left),
+            bottom: self.bottom.max(other.bottom),
+            right: self.right.min(other.right),
+            top: self.top.min(other.top),
+        }
+    }
+
+    // The smallest rectangle containing both.
+    pub fn union(&self, other: &Rect) -> Rect {
+        Rect {
+            left: self.left.min(other.left),
+            bottom: self.bottom.min(other.bottom),
+            right: self.right.max(other.right),
+            top: self.top.max(other.top),
+        }
+    }
+
+    // Liang-Barsky. Returns the range of t in [0, 1] for which the point
+    // p0 + t * (p1 - p0) lies inside the rectangle, or None if the segment
+    // misses it entirely.
+    pub fn clip_segment(&self, p0: &Point2, p1: &Point2) -> Option<(f32, f32)> {
+        let (x0, y0) = (p0.x as f32, p0.y as f32);
+        let dx = (p1.x - p0.x) as f32;
+        let dy = (p1.y - p0.y) as f32;
+        let mut t0: f32 = 0.;
+        let mut t1: f32 = 1.;
+        for (p, q) in [
+            (-dx, x0 - self.left as f32),
+            (dx, self.right as f32 - x0),
+            (-dy, y0 - self.bottom as f32),
+            (dy, self.top as f32 - y0),
+        ] {
+            if p == 0. {
+                // Parallel to this edge, so either entirely inside or outside.
+                if q < 0. {
+                    return None;
+                }
+            } else if p < 0. {
+                t0 = t0.max(q / p);
+            } else {
+                t1 = t1.min(q / p);
+            }
+        }
+        if t0 > t1 {
+            None
+        } else {
+            Some((t0, t1))
+        }
+    }
+}
+
 /// A point in 3D space.
 #[derive(Clone, Copy, Debug)]
 pub struct Point3 {
@@ -28,6 +105,26 @@ impl Point3 {
     pub fn new(x: f32, y: f32, z: f32) -> Point3 {
         Point3 { x, y, z }
     }
+
+    pub fn dot(&self, other: Point3) -> f32 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn cross(&self, other: Point3) -> Point3 {
+        Point3 {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x,
+        }
+    }
+
+    pub fn length(&self) -> f32 {
+        self.dot(*self).sqrt()
+    }
+
+    pub fn normalize(&self) -> Point3 {
+        *self * (1. / self.length())
+    }
 }
 
 impl Default for Point3 {
@@ -60,6 +157,26 @@ impl Neg for Point3 {
     }
 }
 
+impl Add for Point3 {
+    type Output = Point3;
+
+    fn add(self, rhs: Point3) -> Point3 {
+        Point3 {
+            x: self.x + rhs.x,
+            y: self.y + rhs.y,
+            z: self.z + rhs.z,
+        }
+    }
+}
+
+impl Sub for Point3 {
+    type Output = Point3;
+
+    fn sub(self, rhs: Point3) -> Point3 {
+        self + -rhs
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Matrix4 {
     pub x: [f32; 4],
@@ -166,6 +283,42 @@ impl Matrix4 {
         )
     }
 
+    // Transforms a direction rather than a point, i.e. ignores translation.
+    pub fn mul_dir(&self, d: Point3) -> Point3 {
+        let v = self.mul_v(Vector4::new(d.x, d.y, d.z, 0.));
+        Point3::new(v.x, v.y, v.z)
+    }
+
+    // The largest factor by which this matrix scales lengths along any axis,
+    // assuming it's made of rotations, translations and scales.
+    pub fn max_scale(&self) -> f32 {
+        let axis_length = |c: &[f32; 4]| Point3::new(c[0], c[1], c[2]).length();
+        axis_length(&self.x)
+            .max(axis_length(&self.y))
+            .max(axis_length(&self.z))
+    }
+
+    // The inverse of a matrix whose last row is (0, 0, 0, 1), i.e. that
+    // doesn't project.
+    pub fn inverse_affine(&self) -> Matrix4 {
+        let col = |c: &[f32; 4]| Point3::new(c[0], c[1], c[2]);
+        let (a, b, c) = (col(&self.x), col(&self.y), col(&self.z));
+        let det = a.dot(b.cross(c));
+        // The rows of the inverse of the 3x3 part.
+        let rows = [b.cross(c), c.cross(a), a.cross(b)].map(|r| r * (1. / det));
+        let row = |r: Point3| Vector4::new(r.x, r.y, r.z, 0.);
+        let mut inverse = Matrix4::from_cols(
+            row(rows[0]),
+            row(rows[1]),
+            row(rows[2]),
+            Vector4::new(0., 0., 0., 1.),
+        )
+        .transpose();
+        let t = -inverse.mul_dir(col(&self.w));
+        inverse.w = [t.x, t.y, t.z, 1.];
+        inverse
+    }
+
     pub fn transpose(&self) -> Matrix4 {
         Matrix4::from_cols(
             Vector4::new(self.x[0], self.y[0], self.z[0], self.w[0]),
@@ -236,20 +389,104 @@ impl From<[f32; 4]> for Vector4 {
 // they represent pixels, while the values of the dependent variable d
 // are always floating point values, as they represent values of a generic
 // linear function.
+#[allow(dead_code)]
 pub fn interpolate(i0: i32, d0: f32, i1: i32, d1: f32) -> Vec<f32> {
     if i0 == i1 {
         return vec![d0];
     }
     let mut values = Vec::new();
     let a = (d1 - d0) / (i1 as f32 - i0 as f32);
-    let mut d = d0;
-    for _ in i0..i1 + 1 {
-        values.push(d);
-        d += a;
+    for i in i0..i1 + 1 {
+        // Rather than accumulating a, which would make each value depend on
+        // the rounding errors of the previous ones.
+        values.push(d0 + a * (i - i0) as f32);
     }
     values
 }
 
+// How much a value that varies linearly across the triangle (p0, p1, p2),
+// with values `d` at its vertices, changes from one pixel to the next, in x
+// and in y. Zero for degenerate triangles.
+pub fn gradient(p0: &Point2, p1: &Point2, p2: &Point2, d: [f32; 3]) -> (f32, f32) {
+    let (x1, y1) = ((p1.x - p0.x) as f32, (p1.y - p0.y) as f32);
+    let (x2, y2) = ((p2.x - p0.x) as f32, (p2.y - p0.y) as f32);
+    let det = x1 * y2 - x2 * y1;
+    if det == 0. {
+        return (0., 0.);
+    }
+    let (d1, d2) = (d[1] - d[0], d[2] - d[0]);
+    ((d1 * y2 - d2 * y1) / det, (x1 * d2 - x2 * d1) / det)
+}
+
+/// An allocation-free version of `interpolate` that computes N dependent
+/// values at once. Yields the same values, in the same order, and since each
+/// one is computed from its index, skipping some with `advance` doesn't
+/// change the others. Tiled rendering relies on that.
+#[derive(Clone, Copy, Debug)]
+pub struct MultiInterpolator<const N: usize> {
+    start: [f32; N],
+    steps: [f32; N],
+    index: i32,
+    len: i32,
+}
+
+impl<const N: usize> MultiInterpolator<N> {
+    pub fn new(i0: i32, d0: [f32; N], i1: i32, d1: [f32; N]) -> Self {
+        let mut steps = [0.; N];
+        if i0 != i1 {
+            for k in 0..N {
+                steps[k] = (d1[k] - d0[k]) / (i1 as f32 - i0 as f32);
+            }
+        }
+        Self {
+            start: d0,
+            steps,
+            index: 0,
+            len: (i1 - i0 + 1).max(0),
+        }
+    }
+
+    /// Skips the next `n` values in constant time.
+    pub fn advance(&mut self, n: i32) {
+        self.index += n.clamp(0, self.len - self.index);
+    }
+}
+
+impl<const N: usize> Iterator for MultiInterpolator<N> {
+    type Item = [f32; N];
+
+    fn next(&mut self) -> Option<[f32; N]> {
+        if self.index == self.len {
+            return None;
+        }
+        let i = self.index as f32;
+        self.index += 1;
+        Some(std::array::from_fn(|k| self.start[k] + self.steps[k] * i))
+    }
+}
+
+/// The single-valued version of `MultiInterpolator`.
+#[derive(Clone, Copy, Debug)]
+pub struct Interpolator(MultiInterpolator<1>);
+
+impl Interpolator {
+    pub fn new(i0: i32, d0: f32, i1: i32, d1: f32) -> Self {
+        Self(MultiInterpolator::new(i0, [d0], i1, [d1]))
+    }
+
+    pub fn advance(&mut self, n: i32) {
+        self.0.advance(n)
+    }
+}
+
+impl Iterator for Interpolator {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.0.next().map(|[d]| d)
+    }
+}
+
 #[test]
 fn test_matrix_mul_m() {
     assert_eq!(
@@ -277,3 +514,30 @@ fn test_matrix_mul_v() {
         Vector4::new(1., 2., 3., 1.)
     );
 }
+
+#[test]
+fn test_inverse_affine() {
+    let m = Matrix4::from_translation(Point3::new(1., 2., 3.))
+        * Matrix4::from_rotation_y(0.5)
+        * Matrix4::from_scale(2.);
+    let p = Point3::new(-1., 4., 0.5);
+    let q = m.inverse_affine() * (m * p);
+    assert!((q - p).length() < 1e-5);
+}
+
+#[test]
+fn test_interpolator() {
+    for (i0, d0, i1, d1) in [
+        (0, 0., 10, 1.),
+        (-3, 5., 4, -2.),
+        (2, 1., 2, 3.),
+        (5, 0., 3, 1.),
+    ] {
+        let values: Vec<f32> = Interpolator::new(i0, d0, i1, d1).collect();
+        assert_eq!(values, interpolate(i0, d0, i1, d1));
+    }
+
+    let mut it = MultiInterpolator::new(0, [0., 10.], 4, [4., 6.]);
+    it.advance(2);
+    assert_eq!(it.collect::<Vec<_>>(), vec![[2., 8.], [3., 7.], [4., 6.]]);
+}
@@ -0,0 +1,371 @@
+use crate::math::*;
+
+/// The algorithm used by `Canvas` to fill triangles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rasterizer {
+    // Interpolates the x coordinates along the edges and draws horizontal
+    // segments between them, as in Chapter 7.
+    Scanline,
+    // Tests every pixel in the bounding box against the three edges, using
+    // a top-left fill rule so that adjacent triangles never overlap.
+    EdgeFunction,
+}
+
+/// How the edges of filled triangles are anti-aliased.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Antialiasing {
+    Off,
+    // Renders everything at N times the resolution along each axis, then
+    // averages each block of N by N pixels.
+    Supersampling(usize),
+    // Stores N coverage samples per pixel, but only shades each pixel once.
+    Multisampling(usize),
+}
+
+impl Rasterizer {
+    /// Calls `f` for every pixel of the triangle that's inside `clip`, along
+    /// with the N attributes given for each vertex, linearly interpolated.
+    pub fn fill<const N: usize>(
+        &self,
+        p0: &Point2,
+        p1: &Point2,
+        p2: &Point2,
+        attrs: [[f32; N]; 3],
+        clip: Rect,
+        mut f: impl FnMut(i32, i32, [f32; N]),
+    ) {
+        match self {
+            Rasterizer::Scanline => scanline_triangle(p0, p1, p2, attrs, clip, f),
+            Rasterizer::EdgeFunction => {
+                edge_function_triangle(p0, p1, p2, clip, |x, y, w| f(x, y, weigh(&attrs, w)))
+            }
+        }
+    }
+}
+
+fn weigh<const N: usize>(attrs: &[[f32; N]; 3], w: [f32; 3]) -> [f32; N] {
+    std::array::from_fn(|k| w[0] * attrs[0][k] + w[1] * attrs[1][k] + w[2] * attrs[2][k])
+}
+
+/// The numbers of samples per pixel that `sample_pattern` has a pattern for.
+pub const SUPPORTED_SAMPLES: [usize; 4] = [1, 2, 4, 8];
+
+/// A number of samples per pixel that isn't in `SUPPORTED_SAMPLES`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnsupportedSamples(pub usize);
+
+/// The positions of the samples within a pixel, in sixteenths of a pixel from
+/// its center, for each supported number of samples per pixel, or None for
+/// other numbers. These are the standard Direct3D patterns, which avoid
+/// having several samples on the same row or column.
+pub fn sample_pattern(samples: usize) -> Option<&'static [(i32, i32)]> {
+    Some(match samples {
+        1 => &[(0, 0)],
+        2 => &[(4, 4), (-4, -4)],
+        4 => &[(-2, -6), (6, -2), (-6, 2), (2, 6)],
+        8 => &[
+            (1, -3),
+            (-1, 3),
+            (5, 1),
+            (-3, -5),
+            (-5, 5),
+            (-7, -1),
+            (3, 7),
+            (7, -7),
+        ],
+        _ => return None,
+    })
+}
+
+/// Calls `f` for every pixel inside `clip` where at least one of the samples
+/// given by `pattern` is inside the triangle, along with a mask of those
+/// samples (bit i for `pattern[i]`) and the attributes at the center of the
+/// pixel. Always uses edge functions, since coverage is computed per sample.
+pub fn multisample_fill<const N: usize>(
+    p0: &Point2,
+    p1: &Point2,
+    p2: &Point2,
+    attrs: [[f32; N]; 3],
+    clip: Rect,
+    pattern: &[(i32, i32)],
+    mut f: impl FnMut(i32, i32, u32, [f32; N]),
+) {
+    multisample_triangle(p0, p1, p2, clip, pattern, |x, y, mask, w| {
+        f(x, y, mask, weigh(&attrs, w))
+    });
+}
+
+// Walks along one edge of a triangle, yielding x and the attributes for each
+// row it spans.
+struct Edge<const N: usize> {
+    x: Interpolator,
+    attrs: MultiInterpolator<N>,
+}
+
+impl<const N: usize> Edge<N> {
+    fn new(p0: &Point2, a0: [f32; N], p1: &Point2, a1: [f32; N]) -> Self {
+        Self {
+            x: Interpolator::new(p0.y, p0.x as f32, p1.y, p1.x as f32),
+            attrs: MultiInterpolator::new(p0.y, a0, p1.y, a1),
+        }
+    }
+
+    fn advance(mut self, n: i32) -> Self {
+        self.x.advance(n);
+        self.attrs.advance(n);
+        self
+    }
+}
+
+impl<const N: usize> Iterator for Edge<N> {
+    type Item = (f32, [f32; N]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some((self.x.next()?, self.attrs.next()?))
+    }
+}
+
+/// From Chapters 7 and 8: interpolates x and the attributes along the edges
+/// of the triangle, then along each horizontal segment between them.
+pub fn scanline_triangle<const N: usize>(
+    p0: &Point2,
+    p1: &Point2,
+    p2: &Point2,
+    attrs: [[f32; N]; 3],
+    clip: Rect,
+    mut f: impl FnMut(i32, i32, [f32; N]),
+) {
+    // Sort the vertices so that y0 <= y1 <= y2, keeping their attributes along.
+    let mut v = [(p0, attrs[0]), (p1, attrs[1]), (p2, attrs[2])];
+    v.sort_by_key(|(p, _)| p.y);
+    let [(p0, a0), (p1, a1), (p2, a2)] = v;
+
+    let y_start = p0.y.max(clip.bottom);
+    let y_end = p2.y.min(clip.top);
+
+    // The long side goes from p0 to p2; the short sides from p0 to p1, and
+    // then from p1 to p2.
+    let mut long = Edge::new(p0, a0, p2, a2).advance(y_start - p0.y);
+    let mut on_upper_side = y_start >= p1.y;
+    let mut short = if on_upper_side {
+        Edge::new(p1, a1, p2, a2).advance(y_start - p1.y)
+    } else {
+        Edge::new(p0, a0, p1, a1).advance(y_start - p0.y)
+    };
+
+    for y in y_start..y_end + 1 {
+        if y == p1.y && !on_upper_side {
+            short = Edge::new(p1, a1, p2, a2);
+            on_upper_side = true;
+        }
+        let (Some(a), Some(b)) = (long.next(), short.next()) else {
+            return;
+        };
+
+        // Determine which is left and which is right
+        let ((x_left, a_left), (x_right, a_right)) = if b.0 < a.0 { (b, a) } else { (a, b) };
+
+        // Draw the horizontal segment
+        let x_start = x_left as i32;
+        let x_end = x_right as i32;
+        let mut segment = MultiInterpolator::new(x_start, a_left, x_end, a_right);
+        segment.advance(clip.left - x_start);
+        for x in x_start.max(clip.left)..x_end.min(clip.right) + 1 {
+            if let Some(values) = segment.next() {
+                f(x, y, values);
+            }
+        }
+    }
+}
+
+// Twice the signed area of the triangle ABP. Positive if P is to the left of
+// the edge AB, i.e. if ABP is counterclockwise (remember that y points up).
+fn edge_function(a: &Point2, b: &Point2, p: &Point2) -> i64 {
+    (b.x - a.x) as i64 * (p.y - a.y) as i64 - (b.y - a.y) as i64 * (p.x - a.x) as i64
+}
+
+// For a counterclockwise triangle, a top edge is horizontal and goes to the
+// left, and a left edge goes down. Pixels exactly on these edges belong to
+// the triangle; pixels on the other edges belong to its neighbours.
+fn is_top_left(a: &Point2, b: &Point2) -> bool {
+    (a.y == b.y && b.x < a.x) || b.y < a.y
+}
+
+/// Calls `f` with the coordinates of every pixel inside both the triangle
+/// and `clip`, along with the barycentric weights of the three vertices at
+/// that pixel. Triangles with zero area cover no pixels.
+pub fn edge_function_triangle(
+    p0: &Point2,
+    p1: &Point2,
+    p2: &Point2,
+    clip: Rect,
+    mut f: impl FnMut(i32, i32, [f32; 3]),
+) {
+    multisample_triangle(p0, p1, p2, clip, &[(0, 0)], |x, y, _, w| f(x, y, w));
+}
+
+// Like `edge_function_triangle`, but tests each of the sample positions in
+// `pattern` (see `sample_pattern`) and also passes the mask of the samples
+// inside the triangle to `f`. The weights are still those at the center of
+// the pixel, even when it's outside the triangle.
+fn multisample_triangle(
+    p0: &Point2,
+    p1: &Point2,
+    p2: &Point2,
+    clip: Rect,
+    pattern: &[(i32, i32)],
+    mut f: impl FnMut(i32, i32, u32, [f32; 3]),
+) {
+    // Make sure the vertices are counterclockwise, remembering where each
+    // one came from so that the weights can be reported in the same order.
+    let (p1, p2, swapped) = if edge_function(p0, p1, p2) < 0 {
+        (p2, p1, true)
+    } else {
+        (p1, p2, false)
+    };
+    let area = edge_function(p0, p1, p2);
+    if area == 0 {
+        return;
+    }
+
+    let bounds = Rect::new(
+        p0.x.min(p1.x).min(p2.x),
+        p0.y.min(p1.y).min(p2.y),
+        p0.x.max(p1.x).max(p2.x),
+        p0.y.max(p1.y).max(p2.y),
+    )
+    .intersect(&clip);
+    if bounds.left > bounds.right || bounds.bottom > bounds.top {
+        return;
+    }
+
+    // Each edge is named after the vertex opposite to it, whose weight it
+    // determines. Samples on edges that aren't top-left are excluded by
+    // biasing the edge function by one (sixteenth of a pixel).
+    let edges = [(p1, p2), (p2, p0), (p0, p1)];
+    let bias = edges.map(|(a, b)| if is_top_left(a, b) { 0 } else { -1 });
+
+    // The edge functions are linear, so they can be updated incrementally,
+    // and are offset by a constant for each sample.
+    let corner = Point2::new(bounds.left, bounds.bottom);
+    let mut row = edges.map(|(a, b)| edge_function(a, b, &corner));
+    let step_x = edges.map(|(a, b)| -(b.y - a.y) as i64);
+    let step_y = edges.map(|(a, b)| (b.x - a.x) as i64);
+    let offsets: Vec<[i64; 3]> = pattern
+        .iter()
+        .map(|&(dx, dy)| [0, 1, 2].map(|i| step_x[i] * dx as i64 + step_y[i] * dy as i64 + bias[i]))
+        .collect();
+
+    let inv_area = 1. / area as f32;
+    for y in bounds.bottom..bounds.top + 1 {
+        let mut w = row;
+        for x in bounds.left..bounds.right + 1 {
+            let mut mask = 0;
+            for (s, offset) in offsets.iter().enumerate() {
+                if (0..3).all(|i| 16 * w[i] + offset[i] >= 0) {
+                    mask |= 1 << s;
+                }
+            }
+            if mask != 0 {
+                let weights = w.map(|w| w as f32 * inv_area);
+                if swapped {
+                    f(x, y, mask, [weights[0], weights[2], weights[1]]);
+                } else {
+                    f(x, y, mask, weights);
+                }
+            }
+            for i in 0..3 {
+                w[i] += step_x[i];
+            }
+        }
+        for i in 0..3 {
+            row[i] += step_y[i];
+        }
+    }
+}
+
+#[test]
+fn test_rasterize_triangle_fill_rule() {
+    // A fan of triangles around the origin, in both windings, should cover
+    // each pixel of the square exactly once.
+    let corners = [
+        Point2::new(-4, -4),
+        Point2::new(0, -4),
+        Point2::new(4, -4),
+        Point2::new(4, 4),
+        Point2::new(-4, 4),
+    ];
+    let center = Point2::new(1, 0);
+    let clip = Rect::new(-10, -10, 10, 10);
+    let mut coverage = [[0; 21]; 21];
+    for i in 0..corners.len() {
+        let a = &corners[i];
+        let b = &corners[(i + 1) % corners.len()];
+        let (a, b) = if i % 2 == 0 { (a, b) } else { (b, a) };
+        edge_function_triangle(&center, a, b, clip, |x, y, weights| {
+            assert!((weights.iter().sum::<f32>() - 1.).abs() < 1e-5);
+            coverage[(y + 10) as usize][(x + 10) as usize] += 1;
+        });
+    }
+    for y in -10..11 {
+        for x in -10..11 {
+            // The bottom and right edges of the square are left out.
+            let inside = (-4..4).contains(&x) && (-3..5).contains(&y);
+            let expected = if inside { 1 } else { 0 };
+            assert_eq!(coverage[(y + 10) as usize][(x + 10) as usize], expected);
+        }
+    }
+
+    // Degenerate triangles don't cover anything.
+    let p = Point2::new(0, 0);
+    let q = Point2::new(3, 0);
+    edge_function_triangle(&p, &q, &Point2::new(-2, 0), clip, |_, _, _| panic!());
+    edge_function_triangle(&p, &p, &p, clip, |_, _, _| panic!());
+}
+
+#[test]
+fn test_multisample_triangle() {
+    // Two triangles sharing a diagonal cover every sample of the square
+    // exactly once, including those on the diagonal.
+    let pattern = sample_pattern(4).unwrap();
+    let clip = Rect::new(-10, -10, 10, 10);
+    let (a, b, c, d) = (
+        Point2::new(-3, -3),
+        Point2::new(3, -3),
+        Point2::new(3, 3),
+        Point2::new(-3, 3),
+    );
+    let mut coverage = [[0; 21]; 21];
+    for (p0, p1, p2) in [(&a, &b, &c), (&a, &c, &d)] {
+        multisample_triangle(p0, p1, p2, clip, pattern, |x, y, mask, _| {
+            let covered = &mut coverage[(y + 10) as usize][(x + 10) as usize];
+            assert_eq!(*covered & mask, 0);
+            *covered |= mask;
+        });
+    }
+    for y in -10..11 {
+        for x in -10..11 {
+            let expected = match (x, y) {
+                // The corners of the square are a quarter covered, and its
+                // edges half covered.
+                (-3, -3) => 0b1000,
+                (3, -3) => 0b0100,
+                (-3, 3) => 0b0010,
+                (3, 3) => 0b0001,
+                (-3, -2..=2) => 0b1010,
+                (3, -2..=2) => 0b0101,
+                (-2..=2, -3) => 0b1100,
+                (-2..=2, 3) => 0b0011,
+                (-2..=2, -2..=2) => 0b1111,
+                _ => 0,
+            };
+            assert_eq!(coverage[(y + 10) as usize][(x + 10) as usize], expected);
+        }
+    }
+
+    for samples in SUPPORTED_SAMPLES {
+        assert_eq!(sample_pattern(samples).unwrap().len(), samples);
+    }
+    assert_eq!(sample_pattern(3), None);
+    assert_eq!(sample_pattern(0), None);
+}
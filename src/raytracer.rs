@@ -0,0 +1,574 @@
+use std::thread;
+
+use crate::light::*;
+use crate::math::*;
+use crate::scene::{Model, Scene, Triangle};
+use crate::shading::*;
+use crate::texture::*;
+use crate::{Canvas, Color};
+
+// The size of the tiles that are traced in parallel, in pixels.
+const TILE_SIZE: usize = 32;
+
+// Rays that start on a surface ignore hits closer than this, so that they
+// don't hit the surface itself because of rounding errors.
+const EPSILON: f32 = 1e-3;
+
+/// A half-line, made of the points `origin + direction * t` for t >= 0. The
+/// direction isn't necessarily normalized.
+#[derive(Debug, Clone, Copy)]
+pub struct Ray {
+    pub origin: Point3,
+    pub direction: Point3,
+}
+
+impl Ray {
+    pub fn at(&self, t: f32) -> Point3 {
+        self.origin + self.direction * t
+    }
+}
+
+/// A sphere, which only the ray tracer draws.
+#[derive(Debug, Clone, Copy)]
+pub struct Sphere {
+    pub center: Point3,
+    pub radius: f32,
+    pub color: Color,
+    // Matte if None.
+    pub specular: Option<f32>,
+    // How much of its color comes from what it reflects, from 0 to 1.
+    pub reflective: f32,
+    // How much of the light goes through the surface instead, from 0 to 1,
+    // bending according to the refractive index of what's inside (which is
+    // 1 for air). Transparent spheres also let light through to what's in
+    // their shadow.
+    pub transparency: f32,
+    pub refractive_index: f32,
+}
+
+impl Sphere {
+    pub fn new(center: Point3, radius: f32, color: Color) -> Self {
+        Self {
+            center,
+            radius,
+            color,
+            specular: None,
+            reflective: 0.,
+            transparency: 0.,
+            refractive_index: 1.,
+        }
+    }
+
+    pub fn with_reflective(self, reflective: f32) -> Self {
+        Self { reflective, ..self }
+    }
+
+    pub fn with_transparency(self, transparency: f32, refractive_index: f32) -> Self {
+        Self {
+            transparency,
+            refractive_index,
+            ..self
+        }
+    }
+
+    pub fn with_specular(self, specular: f32) -> Self {
+        Self {
+            specular: Some(specular),
+            ..self
+        }
+    }
+
+    // The unit normal at a point of the surface.
+    pub fn normal(&self, p: Point3) -> Point3 {
+        (p - self.center) * (1. / self.radius)
+    }
+
+    // From Listing 2-2: the values of t where the ray enters and leaves the
+    // sphere (possibly behind its origin), or None if it misses it.
+    pub fn intersect(&self, ray: &Ray) -> Option<(f32, f32)> {
+        intersect_sphere(ray, self.center, self.radius)
+    }
+}
+
+// The values of t where the ray enters and leaves the sphere. This is done
+// in double precision, and in a way that avoids cancellation, since a
+// sphere can be huge compared to the distances involved, e.g. to make a
+// floor, and rays that start on its surface mustn't hit it again.
+fn intersect_sphere(ray: &Ray, center: Point3, radius: f32) -> Option<(f32, f32)> {
+    let v = |p: Point3| [p.x as f64, p.y as f64, p.z as f64];
+    let dot = |a: [f64; 3], b: [f64; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+    let (o, c, d) = (v(ray.origin), v(center), v(ray.direction));
+    let co = [o[0] - c[0], o[1] - c[1], o[2] - c[2]];
+    let (len, r) = (dot(co, co).sqrt(), radius as f64);
+    let a = dot(d, d);
+    let half_b = dot(co, d);
+    let c = (len - r) * (len + r);
+    let discriminant = half_b * half_b - a * c;
+    if discriminant < 0. {
+        return None;
+    }
+    let q = -(half_b + discriminant.sqrt().copysign(half_b));
+    if q == 0. {
+        return Some((0., 0.));
+    }
+    let (t1, t2) = ((q / a) as f32, (c / q) as f32);
+    Some((t1.min(t2), t1.max(t2)))
+}
+
+// The Möller–Trumbore algorithm: the t where the ray hits the triangle,
+// along with the barycentric coordinates of the hit, i.e. the weights of
+// the second and third vertices, or None if it misses.
+pub fn intersect_triangle(
+    ray: &Ray,
+    p0: Point3,
+    p1: Point3,
+    p2: Point3,
+) -> Option<(f32, f32, f32)> {
+    let (e1, e2) = (p1 - p0, p2 - p0);
+    let p = ray.direction.cross(e2);
+    let det = e1.dot(p);
+    // The ray is parallel to the triangle.
+    if det == 0. {
+        return None;
+    }
+    let s = ray.origin - p0;
+    let u = s.dot(p) / det;
+    if !(0. ..=1.).contains(&u) {
+        return None;
+    }
+    let q = s.cross(e1);
+    let v = ray.direction.dot(q) / det;
+    if v < 0. || u + v > 1. {
+        return None;
+    }
+    Some((e2.dot(q) / det, u, v))
+}
+
+// Where a ray with unit direction `d` goes through a surface with unit
+// normal `n` facing it, from a material with refractive index `n1` into one
+// with `n2`, according to Snell's law, along with the fraction of the light
+// that's reflected instead, according to Schlick's approximation of the
+// Fresnel equations. All of it is, with no refracted ray, in case of total
+// internal reflection.
+pub fn refract(d: Point3, n: Point3, n1: f32, n2: f32) -> (Option<Point3>, f32) {
+    // There's no surface to speak of, but Schlick's approximation would
+    // still reflect some light at grazing angles.
+    if n1 == n2 {
+        return (Some(d), 0.);
+    }
+    let eta = n1 / n2;
+    let cos_i = -d.dot(n);
+    let k = 1. - eta * eta * (1. - cos_i * cos_i);
+    if k < 0. {
+        return (None, 1.);
+    }
+    let cos_t = k.sqrt();
+    let refracted = d * eta + n * (eta * cos_i - cos_t);
+    // The angle on the side of the less dense material.
+    let cos = if n1 > n2 { cos_t } else { cos_i };
+    let r0 = ((n1 - n2) / (n1 + n2)).powi(2);
+    (Some(refracted), r0 + (1. - r0) * (1. - cos).powi(5))
+}
+
+// An instance, with the transforms between the space of its model and
+// camera space.
+struct Object<'a> {
+    model: &'a Model,
+    to_camera: Matrix4,
+    to_model: Matrix4,
+}
+
+impl Object<'_> {
+    // The closest hit between `t_min` and `t_max`, with the triangle and the
+    // barycentric coordinates of the hit. The ray is transformed into the
+    // space of the model rather than the other way around, which doesn't
+    // change t.
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(f32, &Triangle, f32, f32)> {
+        let model = self.model;
+        let ray = Ray {
+            origin: self.to_model * ray.origin,
+            direction: self.to_model.mul_dir(ray.direction),
+        };
+        match intersect_sphere(&ray, model.bounds_center, model.bounds_radius) {
+            Some((t1, t2)) if t1 < t_max && t2 > t_min => {}
+            _ => return None,
+        }
+        let v = &model.vertices;
+        let mut closest: Option<(f32, &Triangle, f32, f32)> = None;
+        for triangle in &model.triangles {
+            let (i0, i1, i2) = triangle.v;
+            let Some((t, a, b)) = intersect_triangle(&ray, v[i0], v[i1], v[i2]) else {
+                continue;
+            };
+            if t_min < t && t < t_max && closest.is_none_or(|(c, ..)| t < c) {
+                closest = Some((t, triangle, a, b));
+            }
+        }
+        closest
+    }
+}
+
+// What a ray hit.
+#[derive(Clone, Copy)]
+enum Target<'a> {
+    Sphere(&'a Sphere),
+    // With the barycentric coordinates of the hit.
+    Triangle(&'a Object<'a>, &'a Triangle, f32, f32),
+}
+
+// What lighting depends on at a hit, in camera space.
+struct Surface {
+    // Facing where the ray came from.
+    normal: Point3,
+    // Whether the ray came from inside the object, i.e. hit its surface
+    // from behind.
+    inside: bool,
+    color: Color,
+    specular: Option<f32>,
+    reflective: f32,
+    transparency: f32,
+    refractive_index: f32,
+}
+
+// What rays can hit, and the lights, all in camera space.
+struct World<'a> {
+    scene: &'a Scene,
+    spheres: Vec<Sphere>,
+    objects: Vec<Object<'a>>,
+    lights: Vec<Light>,
+}
+
+impl World<'_> {
+    // From Listing 4-2: the closest hit between `t_min` and `t_max`.
+    fn closest_intersection(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(f32, Target<'_>)> {
+        let mut closest: Option<(f32, Target)> = None;
+        for sphere in &self.spheres {
+            let Some((t1, t2)) = sphere.intersect(ray) else {
+                continue;
+            };
+            for t in [t1, t2] {
+                if t_min < t && t < t_max && closest.is_none_or(|(c, _)| t < c) {
+                    closest = Some((t, Target::Sphere(sphere)));
+                }
+            }
+        }
+        for object in &self.objects {
+            let t_max = closest.map_or(t_max, |(c, _)| c);
+            if let Some((t, triangle, a, b)) = object.intersect(ray, t_min, t_max) {
+                closest = Some((t, Target::Triangle(object, triangle, a, b)));
+            }
+        }
+        closest
+    }
+
+    // The surface at the point hit by `ray`.
+    fn surface(&self, ray: &Ray, point: Point3, target: Target) -> Surface {
+        let shading = self.scene.shading;
+        let (outward, surface) = match target {
+            Target::Sphere(sphere) => {
+                let normal = sphere.normal(point);
+                let surface = Surface {
+                    normal,
+                    inside: false,
+                    color: sphere.color,
+                    specular: sphere.specular,
+                    reflective: sphere.reflective,
+                    transparency: sphere.transparency,
+                    refractive_index: sphere.refractive_index,
+                };
+                (normal, surface)
+            }
+            Target::Triangle(object, triangle, a, b) => {
+                let model = object.model;
+                let weights = [1. - a - b, a, b];
+                let lerp =
+                    |p: [Point3; 3]| p[0] * weights[0] + p[1] * weights[1] + p[2] * weights[2];
+                let uv = |k: fn(&(f32, f32)) -> f32| {
+                    (0..3)
+                        .map(|i| k(&triangle.uvs[i]) * weights[i])
+                        .sum::<f32>()
+                };
+                let (u, v) = (uv(|uv| uv.0), uv(|uv| uv.1));
+                let sample =
+                    |texture: &Texture| texture.sample(u, v, 0., self.scene.texture_filter);
+
+                let face_normal = object
+                    .to_camera
+                    .mul_dir(triangle.normal(&model.vertices))
+                    .normalize();
+                let mut normal = match triangle.normals {
+                    Some(normals) if shading != ShadingMode::Flat => {
+                        object.to_camera.mul_dir(lerp(normals)).normalize()
+                    }
+                    _ => face_normal,
+                };
+                if let (Some(normal_map), Some(tangents), true) = (
+                    &model.normal_map,
+                    triangle.tangents,
+                    shading != ShadingMode::Flat,
+                ) {
+                    let tangent = object.to_camera.mul_dir(lerp(tangents));
+                    let handedness = triangle.handedness(&model.vertices);
+                    normal = perturb_normal(normal, tangent, handedness, sample(normal_map));
+                }
+                let surface = Surface {
+                    normal,
+                    inside: false,
+                    color: match &model.texture {
+                        None => Color::from(triangle.color),
+                        Some(texture) => Color::from(sample(texture)),
+                    },
+                    specular: model.specular,
+                    reflective: model.reflective,
+                    transparency: 1. - model.opacity.clamp(0., 1.),
+                    refractive_index: model.refractive_index,
+                };
+                (face_normal, surface)
+            }
+        };
+        if outward.dot(ray.direction) > 0. {
+            Surface {
+                normal: -surface.normal,
+                inside: true,
+                ..surface
+            }
+        } else {
+            surface
+        }
+    }
+
+    // How much of light `i` gets through what's between `point` and the
+    // light, for the lights that cast shadows.
+    fn visibility(&self, point: Point3, i: usize) -> f32 {
+        let light = &self.lights[i];
+        let (direction, t_max) = match light.kind {
+            _ if !light.casts_shadows => return 1.,
+            LightKind::Ambient => return 1.,
+            LightKind::Point(position) => (position - point, 1.),
+            LightKind::Directional(direction) => (direction, f32::INFINITY),
+        };
+        let ray = Ray {
+            origin: point,
+            direction,
+        };
+        let mut visibility = 1.;
+        for sphere in &self.spheres {
+            let Some((t1, t2)) = sphere.intersect(&ray) else {
+                continue;
+            };
+            if [t1, t2].iter().any(|t| EPSILON < *t && *t < t_max) {
+                visibility *= sphere.transparency;
+            }
+        }
+        for object in &self.objects {
+            if object.intersect(&ray, EPSILON, t_max).is_some() {
+                visibility *= 1. - object.model.opacity.clamp(0., 1.);
+            }
+        }
+        visibility
+    }
+
+    // From Listing 4-3, with the reflections of Chapter 5, and refraction:
+    // the color seen along the ray, looking only at the hits between `t_min`
+    // and `t_max`, and following at most `depth` reflections or refractions.
+    fn trace_ray(&self, ray: &Ray, t_min: f32, t_max: f32, depth: usize) -> u32 {
+        let scene = self.scene;
+        let Some((t, target)) = self.closest_intersection(ray, t_min, t_max) else {
+            return scene.fog.map_or(0, |fog| fog.color);
+        };
+        let point = ray.at(t);
+        let surface = self.surface(ray, point, target);
+        let normal = surface.normal;
+        let view = -ray.direction;
+        let mut color = surface.color;
+        if scene.shading != ShadingMode::Unlit {
+            let intensity =
+                compute_lighting_toward(point, normal, view, surface.specular, &self.lights, |i| {
+                    self.visibility(point, i)
+                });
+            // White light only scales the intensity of the color, which
+            // saturates when it's converted to a pixel.
+            if intensity[0] == intensity[1] && intensity[1] == intensity[2] {
+                color.mul(intensity[0]);
+            } else {
+                color.mul_rgb(intensity);
+            }
+        }
+
+        // Transparent surfaces reflect some of the light too, more so at
+        // grazing angles.
+        let (r, tr) = (surface.reflective, surface.transparency);
+        let (refracted, fresnel) = if tr > 0. {
+            let (n1, n2) = if surface.inside {
+                (surface.refractive_index, 1.)
+            } else {
+                (1., surface.refractive_index)
+            };
+            refract(ray.direction.normalize(), normal, n1, n2)
+        } else {
+            (None, 0.)
+        };
+        let reflected_weight = (1. - tr) * r + tr * fresnel;
+        let refracted_weight = tr * (1. - fresnel);
+        let mut color = if depth > 0 && (reflected_weight > 0. || refracted_weight > 0.) {
+            let trace = |direction| {
+                let ray = Ray {
+                    origin: point,
+                    direction,
+                };
+                self.trace_ray(&ray, EPSILON, f32::INFINITY, depth - 1)
+            };
+            let mut colors = vec![(color.into(), (1. - tr) * (1. - r))];
+            if reflected_weight > 0. {
+                let reflected = trace(normal * (2. * normal.dot(view)) - view);
+                colors.push((reflected, reflected_weight));
+            }
+            if let Some(direction) = refracted.filter(|_| refracted_weight > 0.) {
+                colors.push((trace(direction), refracted_weight));
+            }
+            Color::from(mix(colors))
+        } else {
+            color
+        };
+
+        // Reflections are fogged along with what reflects them.
+        if depth == scene.reflection_depth {
+            if let Some(fog) = &scene.fog {
+                color = fog.apply(color, point.z);
+            }
+        }
+        color.into()
+    }
+}
+
+impl Scene {
+    // From Chapters 2 to 5: casts a ray from the camera through the center
+    // of each pixel of the canvas, and sets it to the color of the closest
+    // sphere or instance it hits, lit at that point, with shadows for the
+    // lights that cast them, and mixed with what it reflects and what's seen
+    // through it. Like the rasterizer, this works in camera space, so with
+    // the camera at the origin.
+    pub fn ray_trace(&self, canvas: &mut Canvas) {
+        let m_camera =
+            self.camera.orientation.transpose() * Matrix4::from_translation(-self.camera.position);
+        let world = World {
+            scene: self,
+            spheres: self
+                .spheres
+                .iter()
+                .map(|s| Sphere {
+                    center: m_camera * s.center,
+                    ..*s
+                })
+                .collect(),
+            objects: self
+                .instances
+                .iter()
+                .map(|inst| {
+                    let to_camera = m_camera * inst.transform;
+                    Object {
+                        model: &inst.model,
+                        to_camera,
+                        to_model: to_camera.inverse_affine(),
+                    }
+                })
+                .collect(),
+            lights: self
+                .lights
+                .iter()
+                .map(|l| l.transformed(m_camera))
+                .collect(),
+        };
+        let world = &world;
+        let mut tiles = canvas.split_tiles(TILE_SIZE);
+        let chunk_size = tiles.len().div_ceil(self.threads.max(1)).max(1);
+        thread::scope(|s| {
+            for chunk in tiles.chunks_mut(chunk_size) {
+                s.spawn(move || {
+                    for tile in chunk {
+                        let rect = tile.clip_rect();
+                        for y in rect.bottom..rect.top + 1 {
+                            for x in rect.left..rect.right + 1 {
+                                let ray = self.primary_ray(tile, x, y);
+                                let color =
+                                    world.trace_ray(&ray, 1., f32::INFINITY, self.reflection_depth);
+                                tile.set_pixel(x, y, color);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        canvas.merge_tiles(tiles);
+    }
+
+    // The ray from the camera through the pixel (x, y), in camera space. It
+    // reaches the projection plane at t = 1.
+    pub fn primary_ray(&self, canvas: &Canvas, x: i32, y: i32) -> Ray {
+        Ray {
+            origin: Point3::new(0., 0., 0.),
+            direction: self.unproject_vertex(canvas, x, y, 1.),
+        }
+    }
+}
+
+#[test]
+fn test_refract() {
+    let n = Point3::new(0., 0., -1.);
+    // Straight through, with 4% reflected at the surface of glass.
+    let (t, f) = refract(Point3::new(0., 0., 1.), n, 1., 1.5);
+    assert_eq!(t.unwrap().z, 1.);
+    assert!((f - 0.04).abs() < 1e-6);
+
+    // Towards the normal when entering glass, away from it when leaving.
+    let d = Point3::new(0.6, 0., 0.8);
+    let (t, f) = refract(d, n, 1., 1.5);
+    let t = t.unwrap();
+    assert!((t.length() - 1.).abs() < 1e-6);
+    assert!((t.x - 0.4).abs() < 1e-6);
+    assert!(0.04 < f && f < 1.);
+    let (back, _) = refract(t, n, 1.5, 1.);
+    assert!((back.unwrap().x - 0.6).abs() < 1e-6);
+
+    // Total internal reflection, past the critical angle.
+    let (t, f) = refract(Point3::new(0.8, 0., 0.6), n, 1.5, 1.);
+    assert!(t.is_none());
+    assert_eq!(f, 1.);
+}
+
+#[test]
+fn test_intersect_triangle() {
+    let (p0, p1, p2) = (
+        Point3::new(0., 0., 2.),
+        Point3::new(1., 0., 2.),
+        Point3::new(0., 1., 2.),
+    );
+    let ray = |x, y| Ray {
+        origin: Point3::new(0., 0., 0.),
+        direction: Point3::new(x, y, 1.),
+    };
+    let (t, u, v) = intersect_triangle(&ray(0.1, 0.2), p0, p1, p2).unwrap();
+    assert!((t - 2.).abs() < 1e-6);
+    assert!((u - 0.2).abs() < 1e-6 && (v - 0.4).abs() < 1e-6);
+    // Both sides of the triangle are hit.
+    let (p1, p2) = (p2, p1);
+    assert!(intersect_triangle(&ray(0.1, 0.2), p0, p1, p2).is_some());
+    assert!(intersect_triangle(&ray(0.4, 0.4), p0, p1, p2).is_none());
+    assert!(intersect_triangle(&ray(-0.1, 0.2), p0, p1, p2).is_none());
+}
+
+#[test]
+fn test_intersect_sphere() {
+    let sphere = Sphere::new(Point3::new(0., 0., 5.), 1., Color::red());
+    let ray = Ray {
+        origin: Point3::new(0., 0., 0.),
+        direction: Point3::new(0., 0., 2.),
+    };
+    assert_eq!(sphere.intersect(&ray), Some((2., 3.)));
+    assert_eq!(ray.at(2.).z, 4.);
+    let ray = Ray {
+        direction: Point3::new(0., 1., 2.),
+        ..ray
+    };
+    assert_eq!(sphere.intersect(&ray), None);
+}
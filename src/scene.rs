@@ -1,22 +1,71 @@
 use std::f32::consts::PI;
-use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
+use crate::clip::*;
+use crate::deferred::*;
+use crate::fog::*;
+use crate::light::*;
 use crate::math::*;
+use crate::raytracer::Sphere;
+use crate::shading::*;
+use crate::shadow::*;
+use crate::stencil::*;
+use crate::texture::*;
 use crate::{Canvas, Color};
 
 const PROJECTION_PLANE_Z: f32 = 1.;
 
+// The size of the tiles that are rendered in parallel, in pixels.
+const TILE_SIZE: usize = 64;
+
+// The bit of the stencil buffer that marks the pixels of the selected
+// instance, and how far around them its outline extends, in pixels.
+const SELECTED: u8 = 0x80;
+const OUTLINE_WIDTH: i32 = 3;
+
 pub struct Camera {
     pub position: Point3,
     pub orientation: Matrix4,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderMode {
+    Wireframe,
+    // Solid triangles with hidden surface removal via the depth buffer.
+    Filled,
+}
+
 pub struct Scene {
     pub width: usize,
     pub height: usize,
     pub models: Vec<Model>,
     pub instances: Vec<Instance>,
+    // Only drawn by `ray_trace`.
+    pub spheres: Vec<Sphere>,
     pub camera: Camera,
+    // In world space.
+    pub lights: Vec<Light>,
+    pub render_mode: RenderMode,
+    pub shading: ShadingMode,
+    pub texture_mapping: TextureMapping,
+    pub texture_filter: TextureFilter,
+    // Also used for depth cueing in wireframe mode.
+    pub fog: Option<Fog>,
+    // Wireframes are always drawn with the forward pipeline.
+    pub pipeline: Pipeline,
+    // The number of threads `render` rasterizes with.
+    pub threads: usize,
+    // For the lights that cast shadows.
+    pub shadows: ShadowOptions,
+    // The index of the instance to draw an outline around, if any. It's
+    // drawn with its own stencil state replaced by one marking its pixels.
+    pub selected: Option<usize>,
+    pub outline_color: u32,
+    // How many times the ray tracer follows rays reflected off of surfaces,
+    // or refracted through them.
+    pub reflection_depth: usize,
 }
 
 impl Scene {
@@ -31,7 +80,20 @@ impl Scene {
             height,
             models: Vec::new(),
             instances: Vec::new(),
+            spheres: Vec::new(),
             camera,
+            lights: Vec::new(),
+            render_mode: RenderMode::Filled,
+            shading: ShadingMode::Phong,
+            texture_mapping: TextureMapping::PerspectiveCorrect,
+            texture_filter: TextureFilter::Trilinear,
+            fog: None,
+            pipeline: Pipeline::Forward,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            shadows: ShadowOptions::default(),
+            selected: None,
+            outline_color: Color::rgb(255, 200, 0).into(),
+            reflection_depth: 3,
         }
     }
 
@@ -42,6 +104,20 @@ impl Scene {
         )
     }
 
+    pub fn canvas_to_viewport(&self, canvas: &Canvas, x: i32, y: i32) -> (f32, f32) {
+        (
+            x as f32 * self.width as f32 / canvas.width as f32,
+            y as f32 * self.height as f32 / canvas.height as f32,
+        )
+    }
+
+    // The inverse of `project_vertex`: the point in camera space that
+    // projects to the pixel (x, y) and has the given depth.
+    pub fn unproject_vertex(&self, canvas: &Canvas, x: i32, y: i32, z: f32) -> Point3 {
+        let (vx, vy) = self.canvas_to_viewport(canvas, x, y);
+        Point3::new(vx * z / PROJECTION_PLANE_Z, vy * z / PROJECTION_PLANE_Z, z)
+    }
+
     pub fn project_vertex(&self, canvas: &Canvas, v: Point3) -> Point2 {
         // println!("project_vertex {:?}", v);
         self.viewport_to_canvas(
@@ -51,36 +127,550 @@ impl Scene {
         )
     }
 
+    // The near plane and the four planes through the camera and the edges of
+    // the viewport, all facing into the view frustum.
+    pub fn clipping_planes(&self) -> [Plane; 5] {
+        let half_w = self.width as f32 / 2.;
+        let half_h = self.height as f32 / 2.;
+        [
+            Plane::new(Point3::new(0., 0., 1.), -PROJECTION_PLANE_Z),
+            Plane::new(Point3::new(PROJECTION_PLANE_Z, 0., half_w), 0.),
+            Plane::new(Point3::new(-PROJECTION_PLANE_Z, 0., half_w), 0.),
+            Plane::new(Point3::new(0., PROJECTION_PLANE_Z, half_h), 0.),
+            Plane::new(Point3::new(0., -PROJECTION_PLANE_Z, half_h), 0.),
+        ]
+    }
+
     // From Listing 10-5.
-    pub fn render_model(&self, model: &Model, transform: Matrix4, canvas: &mut Canvas) {
-        let mut projected = Vec::new();
-        for v in &model.vertices {
-            projected.push(self.project_vertex(canvas, transform * *v))
+    pub fn render_model(
+        &self,
+        model: &Model,
+        transform: Matrix4,
+        planes: &[Plane],
+        lights: &[Light],
+        canvas: &mut Canvas,
+    ) {
+        if let Some(mesh) = self.transform_model(model, transform, planes, canvas) {
+            for t in &mesh.triangles {
+                self.render_triangle(canvas, &mesh, t, lights, &[], None);
+            }
         }
-        for t in &model.triangles {
-            self.render_triangle(canvas, t, &projected);
+    }
+
+    // From Chapter 11. Returns None if the model is entirely outside the
+    // view volume.
+    pub fn transform_model<'a>(
+        &self,
+        model: &'a Model,
+        transform: Matrix4,
+        planes: &[Plane],
+        canvas: &Canvas,
+    ) -> Option<Mesh<'a>> {
+        // Discard the whole model if its bounding sphere is outside the
+        // frustum, and only clip triangles against the planes that it crosses.
+        let center = transform * model.bounds_center;
+        let radius = model.bounds_radius * transform.max_scale();
+        let mut crossed = Vec::new();
+        for plane in planes {
+            let d = plane.signed_distance(center);
+            if d < -radius {
+                return None;
+            } else if d < radius {
+                crossed.push(plane);
+            }
         }
+
+        let mut transformed: Vec<Point3> = model.vertices.iter().map(|v| transform * *v).collect();
+        let mut triangles: Vec<Triangle> = model
+            .triangles
+            .iter()
+            .filter(|t| !(model.cull_back_faces && t.is_back_facing(&transformed)))
+            .map(|t| {
+                // Bring the normals into camera space too, falling back to
+                // the face normal if the model doesn't have any.
+                let normals = match t.normals {
+                    Some(normals) => normals.map(|n| transform.mul_dir(n).normalize()),
+                    None => [t.normal(&transformed).normalize(); 3],
+                };
+                Triangle {
+                    normals: Some(normals),
+                    tangents: t.tangents.map(|t| t.map(|v| transform.mul_dir(v))),
+                    ..*t
+                }
+            })
+            .collect();
+        for plane in crossed {
+            triangles = clip_triangles(&triangles, &mut transformed, plane);
+        }
+
+        // Vertices that were clipped against the right or bottom planes can
+        // project one pixel past the edge of the canvas.
+        let projected: Vec<Point2> = transformed
+            .iter()
+            .map(|v| canvas.clamp(self.project_vertex(canvas, *v)))
+            .collect();
+        Some(Mesh {
+            model,
+            triangles,
+            transformed,
+            projected,
+            stencil: StencilState::default(),
+        })
     }
 
     // From Listing 10-5.
     #[allow(dead_code)]
     pub fn render(&self, canvas: &mut Canvas) {
-        canvas.fill(0);
+        // The background is infinitely far, so it's hidden by any fog.
+        canvas.fill(self.fog.map_or(0, |fog| fog.color));
         let m_camera =
             self.camera.orientation.transpose() * Matrix4::from_translation(-self.camera.position);
-        for inst in &self.instances {
-            let m = m_camera * inst.transform;
-            self.render_model(inst.model.as_ref(), m, canvas);
+        let planes = self.clipping_planes();
+        let lights: Vec<Light> = self
+            .lights
+            .iter()
+            .map(|l| l.transformed(m_camera))
+            .collect();
+
+        // Translucent models are drawn last, from back to front, so that
+        // whatever is behind them has already been drawn.
+        let mut instances: Vec<(&Instance, Matrix4)> = self
+            .instances
+            .iter()
+            .map(|inst| (inst, m_camera * inst.transform))
+            .collect();
+        let depth = |(inst, m): &(&Instance, Matrix4)| (*m * inst.model.bounds_center).z;
+        instances.sort_by(|a, b| {
+            let (ta, tb) = (a.0.model.opacity < 1., b.0.model.opacity < 1.);
+            if ta && tb {
+                depth(b).total_cmp(&depth(a))
+            } else {
+                ta.cmp(&tb)
+            }
+        });
+        let shadow_maps = self.shadow_maps(&instances, &lights);
+        let selected = self.selected.and_then(|i| self.instances.get(i));
+        let meshes: Vec<Mesh> = instances
+            .into_iter()
+            .filter_map(|(inst, m)| {
+                let mut mesh = self.transform_model(inst.model.as_ref(), m, &planes, canvas)?;
+                mesh.stencil = match selected {
+                    Some(s) if std::ptr::eq(s, inst) => StencilState::write(SELECTED),
+                    // Whatever is drawn over the selected instance hides
+                    // it, so it unmarks the pixels.
+                    Some(_) if !inst.stencil.is_enabled() => StencilState {
+                        write_mask: SELECTED,
+                        ..StencilState::write(0)
+                    },
+                    _ => inst.stencil,
+                };
+                Some(mesh)
+            })
+            .collect();
+        if self.pipeline == Pipeline::Deferred && self.render_mode == RenderMode::Filled {
+            self.render_deferred(canvas, &meshes, &lights, &shadow_maps);
+        } else if self.threads > 1 {
+            self.render_tiled(canvas, &meshes, &lights, &shadow_maps);
+        } else {
+            for mesh in &meshes {
+                for t in &mesh.triangles {
+                    self.render_triangle(canvas, mesh, t, &lights, &shadow_maps, None);
+                }
+            }
+        }
+        canvas.stencil = StencilState::default();
+        if selected.is_some() {
+            self.draw_outline(canvas);
         }
     }
 
-    pub fn render_triangle(&self, canvas: &mut Canvas, triangle: &Triangle, projected: &[Point2]) {
-        println!("{:?}", triangle);
-        canvas.draw_wireframe(
-            &projected[triangle.v.0],
-            &projected[triangle.v.1],
-            &projected[triangle.v.2],
-            triangle.color,
+    // Draws the outline of the selected instance around the pixels marked
+    // in the stencil buffer. The stencil test keeps it off of the instance,
+    // including the samples of its edges with multisampling.
+    fn draw_outline(&self, canvas: &mut Canvas) {
+        let marked = |x, y| canvas.stencil_at(x, y).is_some_and(|s| s & SELECTED != 0);
+        let r = OUTLINE_WIDTH;
+        let rect = canvas.clip_rect();
+        let mut outline = Vec::new();
+        for y in rect.bottom..rect.top + 1 {
+            for x in rect.left..rect.right + 1 {
+                if marked(x, y) {
+                    continue;
+                }
+                let near = (-r..r + 1).any(|dy| {
+                    (-r..r + 1).any(|dx| dx * dx + dy * dy <= r * r && marked(x + dx, y + dy))
+                });
+                if near {
+                    outline.push((x, y));
+                }
+            }
+        }
+        canvas.stencil = StencilState::only_where(false, SELECTED, SELECTED);
+        for (x, y) in outline {
+            canvas.set_pixel(x, y, self.outline_color);
+        }
+        canvas.stencil = StencilState::default();
+    }
+
+    // Rasterizes the opaque meshes into a G-buffer, without lighting, then
+    // lights what's visible, and finally draws the translucent meshes over
+    // it as usual. Expects the opaque meshes first, as sorted by `render`.
+    fn render_deferred(
+        &self,
+        canvas: &mut Canvas,
+        meshes: &[Mesh],
+        lights: &[Light],
+        shadow_maps: &[Option<ShadowMap>],
+    ) {
+        let mut gbuffer = GBuffer::new(canvas.width, canvas.height);
+        let translucent = meshes.iter().position(|m| m.model.opacity < 1.);
+        let (opaque, translucent) = meshes.split_at(translucent.unwrap_or(meshes.len()));
+        for mesh in opaque {
+            for t in &mesh.triangles {
+                self.render_triangle(canvas, mesh, t, &[], &[], Some(&mut gbuffer));
+            }
+        }
+        canvas.stencil = StencilState::default();
+
+        let intensity = if self.shading == ShadingMode::Unlit {
+            vec![[1.; 3]; gbuffer.albedo.len()]
+        } else {
+            let visibility = |i: usize, point, normal| {
+                shadow_maps
+                    .get(i)
+                    .and_then(Option::as_ref)
+                    .map_or(1., |m| m.visibility(point, normal))
+            };
+            gbuffer.light(lights, visibility, self.threads)
+        };
+        let bounds = canvas.bounds();
+        for (i, rgb) in intensity.into_iter().enumerate() {
+            if gbuffer.material_ids[i] == 0 {
+                continue;
+            }
+            let mut color = Color::from(gbuffer.albedo[i]);
+            color.mul_rgb(rgb);
+            if let Some(fog) = &self.fog {
+                color = fog.apply(color, gbuffer.positions[i].z);
+            }
+            let (x, y) = (i % canvas.width, i / canvas.width);
+            canvas.set_pixel(bounds.left + x as i32, bounds.top - y as i32, color.into());
+        }
+
+        for mesh in translucent {
+            for t in &mesh.triangles {
+                self.render_triangle(canvas, mesh, t, lights, shadow_maps, None);
+            }
+        }
+    }
+
+    // The shadow map of each of the lights (in camera space) that cast
+    // shadows. Every instance casts shadows, including those the camera
+    // can't see.
+    fn shadow_maps(
+        &self,
+        instances: &[(&Instance, Matrix4)],
+        lights: &[Light],
+    ) -> Vec<Option<ShadowMap>> {
+        if !lights.iter().any(|l| l.casts_shadows) {
+            return lights.iter().map(|_| None).collect();
+        }
+        let casters: Vec<(Vec<Point3>, &[Triangle])> = instances
+            .iter()
+            .map(|(inst, m)| {
+                let vertices = inst.model.vertices.iter().map(|v| *m * *v).collect();
+                (vertices, &inst.model.triangles[..])
+            })
+            .collect();
+
+        // A sphere around the bounding spheres of all the instances.
+        let spheres: Vec<(Point3, f32)> = instances
+            .iter()
+            .map(|(inst, m)| {
+                (
+                    *m * inst.model.bounds_center,
+                    inst.model.bounds_radius * m.max_scale(),
+                )
+            })
+            .collect();
+        let n = spheres.len().max(1) as f32;
+        let center = spheres
+            .iter()
+            .fold(Point3::default(), |acc, (c, _)| acc + *c)
+            * (1. / n);
+        let radius = spheres
+            .iter()
+            .map(|(c, r)| (*c - center).length() + r)
+            .fold(0., f32::max);
+
+        lights
+            .iter()
+            .map(|light| {
+                if !light.casts_shadows {
+                    return None;
+                }
+                ShadowMap::new(light, &casters, (center, radius), self.shadows)
+            })
+            .collect()
+    }
+
+    // Splits the canvas into tiles and rasterizes them in parallel. Each tile
+    // only draws the triangles that overlap it, but in the same order as the
+    // whole canvas would, so the result is exactly the same.
+    fn render_tiled(
+        &self,
+        canvas: &mut Canvas,
+        meshes: &[Mesh],
+        lights: &[Light],
+        shadow_maps: &[Option<ShadowMap>],
+    ) {
+        let tiles = canvas.split_tiles(TILE_SIZE);
+
+        // Put each triangle in the bins of the tiles that its bounding box
+        // overlaps, widened by as far as the lines drawn in wireframe mode can
+        // reach past its vertices.
+        let bounds = canvas.bounds();
+        let size = TILE_SIZE as i32;
+        let columns = (bounds.right - bounds.left) / size + 1;
+        let rows = (bounds.top - bounds.bottom) / size + 1;
+        let margin = canvas.line_style.reach().ceil() as i32 + 1;
+        let mut bins: Vec<Vec<(&Mesh, &Triangle)>> = vec![Vec::new(); tiles.len()];
+        for mesh in meshes {
+            for t in &mesh.triangles {
+                let (i0, i1, i2) = t.v;
+                let p = [
+                    &mesh.projected[i0],
+                    &mesh.projected[i1],
+                    &mesh.projected[i2],
+                ];
+                let left = p.iter().map(|p| p.x).min().unwrap() - margin;
+                let right = p.iter().map(|p| p.x).max().unwrap() + margin;
+                let bottom = p.iter().map(|p| p.y).min().unwrap() - margin;
+                let top = p.iter().map(|p| p.y).max().unwrap() + margin;
+                let column_range = ((left - bounds.left) / size).max(0)
+                    ..((right - bounds.left) / size).min(columns - 1) + 1;
+                for row in ((bounds.top - top) / size).max(0)
+                    ..((bounds.top - bottom) / size).min(rows - 1) + 1
+                {
+                    for column in column_range.clone() {
+                        bins[(row * columns + column) as usize].push((mesh, t));
+                    }
+                }
+            }
+        }
+
+        // Threads take the next tile to render until there are none left.
+        let work: Vec<_> = tiles.into_iter().zip(bins).map(Mutex::new).collect();
+        let next = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..self.threads.min(work.len()) {
+                scope.spawn(|| {
+                    while let Some(item) = work.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        let (tile, bin) = &mut *item.lock().unwrap();
+                        for (mesh, t) in bin.iter() {
+                            self.render_triangle(tile, mesh, t, lights, shadow_maps, None);
+                        }
+                    }
+                });
+            }
+        });
+        canvas.merge_tiles(
+            work.into_iter()
+                .map(|m| m.into_inner().unwrap().0)
+                .collect(),
+        );
+    }
+
+    pub fn render_triangle(
+        &self,
+        canvas: &mut Canvas,
+        mesh: &Mesh,
+        triangle: &Triangle,
+        lights: &[Light],
+        shadow_maps: &[Option<ShadowMap>],
+        mut gbuffer: Option<&mut GBuffer>,
+    ) {
+        let (model, transformed, projected) = (mesh.model, &mesh.transformed, &mesh.projected);
+        canvas.stencil = mesh.stencil;
+        let (i0, i1, i2) = triangle.v;
+        let (p0, p1, p2) = (&projected[i0], &projected[i1], &projected[i2]);
+        if self.render_mode == RenderMode::Wireframe {
+            match &self.fog {
+                None => canvas.draw_wireframe(p0, p1, p2, triangle.color),
+                Some(fog) => {
+                    // Depth cueing. 1/z is linear in screen space, so its
+                    // value anywhere on the edges follows from its gradient.
+                    let iz = [i0, i1, i2].map(|i| 1. / transformed[i].z);
+                    let (gx, gy) = gradient(p0, p1, p2, iz);
+                    let color = |x: f32, y: f32| {
+                        let iz = iz[0] + gx * (x - p0.x as f32) + gy * (y - p0.y as f32);
+                        let z = 1. / iz.max(f32::MIN_POSITIVE);
+                        fog.apply(Color::from(triangle.color), z).into()
+                    };
+                    canvas.draw_wireframe_shaded(p0, p1, p2, &color);
+                }
+            }
+            return;
+        }
+
+        let vertices = [transformed[i0], transformed[i1], transformed[i2]];
+        let normals = triangle
+            .normals
+            .unwrap_or([triangle.normal(transformed).normalize(); 3]);
+
+        // Lighting computed once per triangle (flat) or per vertex (Gouraud).
+        // Shadows depend on the position of each pixel, so with shadows, flat
+        // lighting is computed for every pixel too, while Gouraud shading
+        // interpolates the lighting both with and without the shadow-casting
+        // lights, and mixes them according to how much they're in shadow.
+        // `shadow_maps` can be shorter than `lights`, e.g. empty.
+        let shadow_map = |i: usize| shadow_maps.get(i).and_then(Option::as_ref);
+        let shadow_casters = shadow_maps.iter().filter(|m| m.is_some()).count();
+        let lit = |_| 1.;
+        let unlit = |i: usize| if shadow_map(i).is_some() { 0. } else { 1. };
+        let center = (vertices[0] + vertices[1] + vertices[2]) * (1. / 3.);
+        let face_normal = triangle.normal(transformed).normalize();
+        let flat_intensity = if self.shading == ShadingMode::Flat {
+            compute_lighting(center, face_normal, model.specular, lights, lit)
+        } else {
+            [1.; 3]
+        };
+        let (vertex_intensity, vertex_unlit) = if self.shading == ShadingMode::Gouraud {
+            let light = |i: usize, visibility: &dyn Fn(usize) -> f32| {
+                compute_lighting(vertices[i], normals[i], model.specular, lights, visibility)
+            };
+            (
+                [0, 1, 2].map(|i| light(i, &lit)),
+                [0, 1, 2].map(|i| light(i, &unlit)),
+            )
+        } else {
+            ([[1.; 3]; 3], [[1.; 3]; 3])
+        };
+
+        // For normal mapping.
+        let tangents = triangle.tangents.unwrap_or([Point3::default(); 3]);
+        let handedness = triangle.handedness(transformed);
+
+        // Every attribute is divided by z (i.e. multiplied by 1/z) so that it
+        // can be interpolated linearly in screen space, except for texture
+        // coordinates when demonstrating affine texture mapping.
+        let attrs = [0, 1, 2].map(|i| {
+            let iz = 1. / vertices[i].z;
+            let uv_scale = match self.texture_mapping {
+                TextureMapping::Affine => 1.,
+                TextureMapping::PerspectiveCorrect => iz,
+            };
+            [
+                iz,
+                triangle.uvs[i].0 * uv_scale,
+                triangle.uvs[i].1 * uv_scale,
+                normals[i].x * iz,
+                normals[i].y * iz,
+                normals[i].z * iz,
+                vertex_intensity[i][0] * iz,
+                vertex_intensity[i][1] * iz,
+                vertex_intensity[i][2] * iz,
+                vertex_unlit[i][0] * iz,
+                vertex_unlit[i][1] * iz,
+                vertex_unlit[i][2] * iz,
+                tangents[i].x * iz,
+                tangents[i].y * iz,
+                tangents[i].z * iz,
+            ]
+        });
+
+        // How 1/z and the texture coordinates change from one pixel to the
+        // next, which tells which mip level of the texture to sample.
+        let [g_iz, g_u, g_v] = [0, 1, 2].map(|k| gradient(p0, p1, p2, attrs.map(|a| a[k])));
+
+        let material = gbuffer.as_mut().map(|gbuffer| {
+            gbuffer.material_id(Material {
+                specular: model.specular,
+            })
+        });
+
+        canvas.rasterize(
+            p0,
+            p1,
+            p2,
+            attrs,
+            |canvas, x, y, mask, [iz, u, v, nx, ny, nz, hr, hg, hb, ur, ug, ub, tx, ty, tz]| {
+                if !canvas.is_closer(x, y, iz) {
+                    canvas.reject_pixel_masked(x, y, mask);
+                    return;
+                }
+                let z = 1. / iz;
+                let (u, v, dx, dy) = match self.texture_mapping {
+                    TextureMapping::Affine => (u, v, (g_u.0, g_v.0), (g_u.1, g_v.1)),
+                    TextureMapping::PerspectiveCorrect => {
+                        // The derivatives of (u/z) / (1/z), and of v likewise.
+                        let (u, v) = (u * z, v * z);
+                        let dx = ((g_u.0 - u * g_iz.0) * z, (g_v.0 - v * g_iz.0) * z);
+                        let dy = ((g_u.1 - u * g_iz.1) * z, (g_v.1 - v * g_iz.1) * z);
+                        (u, v, dx, dy)
+                    }
+                };
+                let sample = |texture: &Texture| {
+                    texture.sample(u, v, texture.lod(dx, dy), self.texture_filter)
+                };
+                let mut color = match &model.texture {
+                    None => Color::from(triangle.color),
+                    Some(texture) => Color::from(sample(texture)),
+                };
+                let point = self.unproject_vertex(canvas, x, y, z);
+                // What to light the pixel with, with per-pixel lighting.
+                let normal = || {
+                    if self.shading == ShadingMode::Flat {
+                        return face_normal;
+                    }
+                    let normal = Point3::new(nx, ny, nz).normalize();
+                    match &model.normal_map {
+                        None => normal,
+                        Some(normal_map) => {
+                            let tangent = Point3::new(tx, ty, tz);
+                            perturb_normal(normal, tangent, handedness, sample(normal_map))
+                        }
+                    }
+                };
+                if let (Some(gbuffer), Some(material)) = (&mut gbuffer, material) {
+                    if canvas.set_pixel_masked_with_depth(x, y, mask, iz, color.into()) {
+                        let i = canvas.region_index(x, y).unwrap();
+                        gbuffer.set(i, point, normal(), color.into(), material);
+                    }
+                    return;
+                }
+                let visibility =
+                    |i: usize| shadow_map(i).map_or(1., |m| m.visibility(point, face_normal));
+                color.mul_rgb(match self.shading {
+                    ShadingMode::Unlit => [1.; 3],
+                    ShadingMode::Flat if shadow_casters > 0 => {
+                        compute_lighting(center, face_normal, model.specular, lights, visibility)
+                    }
+                    ShadingMode::Flat => flat_intensity,
+                    ShadingMode::Gouraud if shadow_casters > 0 => {
+                        let v = (0..lights.len())
+                            .filter(|i| shadow_map(*i).is_some())
+                            .map(visibility)
+                            .sum::<f32>()
+                            / shadow_casters as f32;
+                        let (lit, unlit) = ([hr, hg, hb], [ur, ug, ub]);
+                        std::array::from_fn(|k| (unlit[k] + (lit[k] - unlit[k]) * v) * z)
+                    }
+                    ShadingMode::Gouraud => [hr * z, hg * z, hb * z],
+                    ShadingMode::Phong => {
+                        compute_lighting(point, normal(), model.specular, lights, visibility)
+                    }
+                });
+                if let Some(fog) = &self.fog {
+                    color = fog.apply(color, z);
+                }
+                if model.opacity < 1. {
+                    color.a = (model.opacity.max(0.) * 255.).round() as u8;
+                    canvas.blend_pixel_masked_with_depth(x, y, mask, iz, color, 1.);
+                } else {
+                    canvas.set_pixel_masked_with_depth(x, y, mask, iz, color.into());
+                }
+            },
         );
     }
 
@@ -173,6 +763,17 @@ impl Scene {
 pub struct Triangle {
     pub v: (usize, usize, usize),
     pub color: u32,
+    // Texture coordinates for each of the three vertices. They're stored
+    // here rather than in the model because a vertex shared by several
+    // triangles (e.g. a corner of a cube) can map to different texels in each.
+    pub uvs: [(f32, f32); 3],
+    // Normals at each of the three vertices, for smooth shading. If they're
+    // missing, the normal of the triangle itself is used instead.
+    pub normals: Option<[Point3; 3]>,
+    // Unit vectors at each of the three vertices, perpendicular to the
+    // normals and pointing towards increasing u, for normal mapping. See
+    // `Model::compute_tangents`.
+    pub tangents: Option<[Point3; 3]>,
 }
 
 impl Triangle {
@@ -180,6 +781,75 @@ impl Triangle {
         Self {
             v,
             color: color.into(),
+            uvs: [(0., 0.); 3],
+            normals: None,
+            tangents: None,
+        }
+    }
+
+    pub fn with_uvs(self, uvs: [(f32, f32); 3]) -> Self {
+        Self { uvs, ..self }
+    }
+
+    pub fn with_normals(self, normals: [Point3; 3]) -> Self {
+        Self {
+            normals: Some(normals),
+            ..self
+        }
+    }
+
+    // The same triangle, starting from vertex `first` (0, 1 or 2) instead.
+    pub fn rotated(&self, first: usize) -> Self {
+        let v = [self.v.0, self.v.1, self.v.2];
+        let (i0, i1, i2) = (first % 3, (first + 1) % 3, (first + 2) % 3);
+        Self {
+            v: (v[i0], v[i1], v[i2]),
+            uvs: [self.uvs[i0], self.uvs[i1], self.uvs[i2]],
+            normals: self.normals.map(|n| [n[i0], n[i1], n[i2]]),
+            tangents: self.tangents.map(|t| [t[i0], t[i1], t[i2]]),
+            ..*self
+        }
+    }
+
+    // Faces outwards if the vertices are in clockwise order when seen from
+    // the front, as they are in `Model::cube`.
+    pub fn normal(&self, vertices: &[Point3]) -> Point3 {
+        let (a, b, c) = (vertices[self.v.0], vertices[self.v.1], vertices[self.v.2]);
+        (b - a).cross(c - a)
+    }
+
+    // From Chapter 12. Expects the vertices in camera space, so that the
+    // camera is at the origin.
+    pub fn is_back_facing(&self, vertices: &[Point3]) -> bool {
+        let to_camera = -vertices[self.v.0];
+        self.normal(vertices).dot(to_camera) <= 0.
+    }
+
+    // The directions in which u and v increase across the triangle, which
+    // are zero if its texture coordinates are degenerate.
+    pub fn uv_directions(&self, vertices: &[Point3]) -> (Point3, Point3) {
+        let (a, b, c) = (vertices[self.v.0], vertices[self.v.1], vertices[self.v.2]);
+        let (e1, e2) = (b - a, c - a);
+        let (du1, dv1) = (self.uvs[1].0 - self.uvs[0].0, self.uvs[1].1 - self.uvs[0].1);
+        let (du2, dv2) = (self.uvs[2].0 - self.uvs[0].0, self.uvs[2].1 - self.uvs[0].1);
+        let det = du1 * dv2 - du2 * dv1;
+        if det == 0. {
+            return (Point3::default(), Point3::default());
+        }
+        (
+            (e1 * dv2 - e2 * dv1) * (1. / det),
+            (e2 * du1 - e1 * du2) * (1. / det),
+        )
+    }
+
+    // Whether the direction of increasing v is on the left of the tangent
+    // when looking at the front of the triangle (1) or on its right (-1).
+    pub fn handedness(&self, vertices: &[Point3]) -> f32 {
+        let (t, b) = self.uv_directions(vertices);
+        if self.normal(vertices).cross(t).dot(b) < 0. {
+            -1.
+        } else {
+            1.
         }
     }
 }
@@ -187,13 +857,100 @@ impl Triangle {
 pub struct Model {
     pub vertices: Vec<Point3>,
     pub triangles: Vec<Triangle>,
+    pub bounds_center: Point3,
+    pub bounds_radius: f32,
+    // Should be turned off for open meshes, whose back faces can be visible.
+    pub cull_back_faces: bool,
+    // If set, triangles are drawn with this texture instead of their color.
+    pub texture: Option<Arc<Texture>>,
+    // If set, perturbs the normals of Phong-shaded triangles to show bumps
+    // and grooves that aren't in the geometry. Each texel is a unit normal
+    // in tangent space, with x, y and z in [-1, 1] mapped to red, green and
+    // blue in [0, 255]: x points towards increasing u, y towards increasing
+    // v (i.e. down the texture) and z away from the surface.
+    pub normal_map: Option<Arc<Texture>>,
+    // The specular exponent; the higher, the shinier. Matte if None.
+    pub specular: Option<f32>,
+    // From 0 (invisible) to 1 (opaque). Translucent models are blended over
+    // what's behind them, and don't hide what's drawn after them.
+    pub opacity: f32,
+    // Like those of `Sphere`, only used by the ray tracer: how much of its
+    // color comes from what it reflects, and the refractive index of what's
+    // inside of it, for the light that its translucency lets through.
+    pub reflective: f32,
+    pub refractive_index: f32,
 }
 
 impl Model {
     pub fn new(vertices: Vec<Point3>, triangles: Vec<Triangle>) -> Self {
-        Self {
+        // A bounding sphere centered on the average of the vertices. It isn't
+        // the tightest possible, but it's good enough for clipping.
+        let n = vertices.len().max(1) as f32;
+        let bounds_center = vertices.iter().fold(Point3::default(), |acc, v| acc + *v) * (1. / n);
+        let bounds_radius = vertices
+            .iter()
+            .map(|v| (*v - bounds_center).length())
+            .fold(0., f32::max);
+        let mut model = Self {
             vertices,
             triangles,
+            bounds_center,
+            bounds_radius,
+            cull_back_faces: true,
+            texture: None,
+            normal_map: None,
+            specular: None,
+            opacity: 1.,
+            reflective: 0.,
+            refractive_index: 1.,
+        };
+        model.compute_tangents();
+        model
+    }
+
+    // Sets the tangents of the triangles from their texture coordinates.
+    // The tangent at each corner is averaged over the triangles that share
+    // both its vertex and its normal, so it's smooth across smooth surfaces
+    // but not across edges like those of a cube.
+    pub fn compute_tangents(&mut self) {
+        let corner_normal = |t: &Triangle, k: usize| match t.normals {
+            Some(normals) => normals[k].normalize(),
+            None => t.normal(&self.vertices).normalize(),
+        };
+        let mut corners = vec![Vec::new(); self.vertices.len()];
+        for (i, t) in self.triangles.iter().enumerate() {
+            for (k, v) in [t.v.0, t.v.1, t.v.2].into_iter().enumerate() {
+                corners[v].push((i, k));
+            }
+        }
+        let directions: Vec<Point3> = self
+            .triangles
+            .iter()
+            .map(|t| t.uv_directions(&self.vertices).0)
+            .collect();
+
+        let tangents: Vec<[Point3; 3]> = self
+            .triangles
+            .iter()
+            .map(|t| {
+                let v = [t.v.0, t.v.1, t.v.2];
+                std::array::from_fn(|k| {
+                    let n = corner_normal(t, k);
+                    let sum = corners[v[k]]
+                        .iter()
+                        .filter(|(j, c)| corner_normal(&self.triangles[*j], *c).dot(n) > 0.999)
+                        .fold(Point3::default(), |acc, (j, _)| acc + directions[*j]);
+                    let tangent = sum - n * n.dot(sum);
+                    if tangent.length() > 0. {
+                        tangent.normalize()
+                    } else {
+                        tangent
+                    }
+                })
+            })
+            .collect();
+        for (t, tangents) in self.triangles.iter_mut().zip(tangents) {
+            t.tangents = Some(tangents);
         }
     }
 
@@ -208,48 +965,83 @@ impl Model {
             Point3::new(-1.0, -1.0, -1.0),
             Point3::new(1.0, -1.0, -1.0),
         ];
+        // Each face maps to the whole texture.
+        let uvs0 = [(0., 0.), (1., 0.), (1., 1.)];
+        let uvs1 = [(0., 0.), (1., 1.), (0., 1.)];
         let triangles = vec![
-            Triangle::new((0, 1, 2), Color::red()),
-            Triangle::new((0, 2, 3), Color::red()),
-            Triangle::new((4, 0, 3), Color::green()),
-            Triangle::new((4, 3, 7), Color::green()),
-            Triangle::new((5, 4, 7), Color::blue()),
-            Triangle::new((5, 7, 6), Color::blue()),
-            Triangle::new((1, 5, 6), Color::yellow()),
-            Triangle::new((1, 6, 2), Color::yellow()),
-            Triangle::new((4, 5, 1), Color::purple()),
-            Triangle::new((4, 1, 0), Color::purple()),
-            Triangle::new((2, 6, 7), Color::cyan()),
-            Triangle::new((2, 7, 3), Color::cyan()),
+            Triangle::new((0, 1, 2), Color::red()).with_uvs(uvs0),
+            Triangle::new((0, 2, 3), Color::red()).with_uvs(uvs1),
+            Triangle::new((4, 0, 3), Color::green()).with_uvs(uvs0),
+            Triangle::new((4, 3, 7), Color::green()).with_uvs(uvs1),
+            Triangle::new((5, 4, 7), Color::blue()).with_uvs(uvs0),
+            Triangle::new((5, 7, 6), Color::blue()).with_uvs(uvs1),
+            Triangle::new((1, 5, 6), Color::yellow()).with_uvs(uvs0),
+            Triangle::new((1, 6, 2), Color::yellow()).with_uvs(uvs1),
+            Triangle::new((4, 5, 1), Color::purple()).with_uvs(uvs0),
+            Triangle::new((4, 1, 0), Color::purple()).with_uvs(uvs1),
+            Triangle::new((2, 6, 7), Color::cyan()).with_uvs(uvs0),
+            Triangle::new((2, 7, 3), Color::cyan()).with_uvs(uvs1),
         ];
 
         Self::new(vertices, triangles)
     }
 }
 
+/// A model as seen from the camera, ready to be rasterized: its vertices in
+/// camera space and projected on the canvas, and its visible triangles after
+/// clipping, which may have added vertices.
+pub struct Mesh<'a> {
+    pub model: &'a Model,
+    pub triangles: Vec<Triangle>,
+    pub transformed: Vec<Point3>,
+    pub projected: Vec<Point2>,
+    pub stencil: StencilState,
+}
+
 pub struct Instance {
-    pub model: Rc<Model>,
+    pub model: Arc<Model>,
     pub transform: Matrix4,
+    // How the instance tests and updates the stencil buffer, e.g. to only
+    // draw it inside a portal that another instance marked.
+    pub stencil: StencilState,
 }
 
 impl Instance {
-    pub fn new(model: Rc<Model>, position: Point3, orientation: Matrix4, scale: f32) -> Self {
+    pub fn new(model: Arc<Model>, position: Point3, orientation: Matrix4, scale: f32) -> Self {
         let transform =
             Matrix4::from_translation(position) * orientation * Matrix4::from_scale(scale);
-        Self { model, transform }
+        Self {
+            model,
+            transform,
+            stencil: StencilState::default(),
+        }
     }
 }
 
 impl Color {
-    fn rgb(r: u8, g: u8, b: u8) -> Color {
-        Color { r, g, b, h: 1.0 }
+    pub fn rgb(r: u8, g: u8, b: u8) -> Color {
+        Color::rgba(r, g, b, 255)
     }
 
-    #[allow(dead_code)]
-    fn mul(&mut self, h: f32) {
+    pub fn rgba(r: u8, g: u8, b: u8, a: u8) -> Color {
+        Color { r, g, b, a, h: 1.0 }
+    }
+
+    pub fn mul(&mut self, h: f32) {
         self.h *= h;
     }
 
+    // Scales each channel separately, e.g. by the intensity of colored light.
+    pub fn mul_rgb(&mut self, i: [f32; 3]) {
+        let scale = |c: u8, i: f32| (c as f32 * self.h * i).min(255.) as u8;
+        *self = Color::rgba(
+            scale(self.r, i[0]),
+            scale(self.g, i[1]),
+            scale(self.b, i[2]),
+            self.a,
+        );
+    }
+
     pub fn blue() -> Color {
         Color::rgb(0, 0, 255)
     }
@@ -270,11 +1062,14 @@ impl Color {
     }
 }
 
+// Packs the color as 0x00RRGGBB, dropping its alpha.
 impl From<Color> for u32 {
     fn from(c: Color) -> Self {
-        let r = ((c.r as f32 * c.h) as u32) << 16;
-        let g = ((c.g as f32 * c.h) as u32) << 8;
-        let b = (c.b as f32 * c.h) as u32;
+        // Clamp so that intensities above 1 saturate instead of bleeding
+        // into the neighbouring channel.
+        let r = ((c.r as f32 * c.h).min(255.) as u32) << 16;
+        let g = ((c.g as f32 * c.h).min(255.) as u32) << 8;
+        let b = (c.b as f32 * c.h).min(255.) as u32;
         r | g | b
     }
 }
@@ -287,3 +1082,47 @@ impl From<u32> for Color {
         Color::rgb(r, g, b)
     }
 }
+
+#[test]
+fn test_back_face_culling() {
+    let cube = Model::cube();
+    let transform = Matrix4::from_translation(Point3::new(0., 0., 5.));
+    let vertices: Vec<Point3> = cube.vertices.iter().map(|v| transform * *v).collect();
+    let front: Vec<_> = cube
+        .triangles
+        .iter()
+        .filter(|t| !t.is_back_facing(&vertices))
+        .map(|t| t.v)
+        .collect();
+    // Only the face at z = -1 is visible from the origin.
+    assert_eq!(front, vec![(5, 4, 7), (5, 7, 6)]);
+}
+
+#[test]
+fn test_normal_mapping() {
+    let cube = Model::cube();
+    for t in &cube.triangles {
+        let normal = t.normal(&cube.vertices).normalize();
+        for tangent in t.tangents.unwrap() {
+            assert!((tangent.length() - 1.).abs() < 1e-6);
+            assert!(tangent.dot(normal).abs() < 1e-6);
+        }
+    }
+    // Across the face at z = 1, u increases towards -x and v towards -y.
+    let front = &cube.triangles[0];
+    let tangent = front.tangents.unwrap()[0];
+    assert_eq!((tangent.x, tangent.y, tangent.z), (-1., 0., 0.));
+    let (_, bitangent) = front.uv_directions(&cube.vertices);
+    assert!(bitangent.y < 0.);
+
+    // A flat texel leaves the normal as it is; others tilt it towards the
+    // tangent or the bitangent.
+    let normal = Point3::new(0., 0., 1.);
+    let handedness = front.handedness(&cube.vertices);
+    let flat = perturb_normal(normal, tangent, handedness, 0x8080FF);
+    assert!((flat - normal).length() < 0.01);
+    let tilted = perturb_normal(normal, tangent, handedness, 0xFF80FF);
+    assert!(tilted.x < -0.5);
+    let tilted = perturb_normal(normal, tangent, handedness, 0x80FFFF);
+    assert!(tilted.y < -0.5);
+}
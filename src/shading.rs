@@ -0,0 +1,134 @@
+use crate::light::*;
+use crate::math::*;
+#[cfg(test)]
+use crate::Color;
+
+/// How lighting is computed across the surface of each triangle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShadingMode {
+    // No lighting at all; triangles are drawn in their own color.
+    Unlit,
+    // Lighting is computed once per triangle, using its face normal.
+    Flat,
+    // Lighting is computed at the vertices and interpolated.
+    Gouraud,
+    // Normals are interpolated and lighting is computed for every pixel.
+    Phong,
+}
+
+// From Chapter 13. The intensity of the light reaching the camera from
+// `point`, a point in camera space on a surface with the given unit `normal`,
+// for each of the red, green and blue channels. The lights must be in camera
+// space too. Surfaces without a `specular` exponent are matte. `visibility`
+// gives the fraction of each (non-ambient) light, by index, that reaches the
+// point, e.g. 0 if it's in its shadow.
+pub fn compute_lighting(
+    point: Point3,
+    normal: Point3,
+    specular: Option<f32>,
+    lights: &[Light],
+    visibility: impl Fn(usize) -> f32,
+) -> [f32; 3] {
+    compute_lighting_toward(point, normal, -point, specular, lights, visibility)
+}
+
+// Like `compute_lighting`, but for the light going in the direction of
+// `to_camera` from the point, which isn't necessarily towards the origin,
+// e.g. when the camera sees the point in a mirror.
+pub fn compute_lighting_toward(
+    point: Point3,
+    normal: Point3,
+    to_camera: Point3,
+    specular: Option<f32>,
+    lights: &[Light],
+    visibility: impl Fn(usize) -> f32,
+) -> [f32; 3] {
+    let mut intensity = [0.; 3];
+    for (i, light) in lights.iter().enumerate() {
+        let rgb = light.rgb_intensity();
+        let l = match light.kind {
+            LightKind::Ambient => {
+                for k in 0..3 {
+                    intensity[k] += rgb[k];
+                }
+                continue;
+            }
+            LightKind::Point(position) => position - point,
+            LightKind::Directional(direction) => direction,
+        };
+
+        // Diffuse reflection
+        let mut factor = 0.;
+        let cos_alpha = normal.dot(l) / l.length();
+        if cos_alpha > 0. {
+            factor += cos_alpha;
+        }
+
+        // Specular reflection
+        if let Some(s) = specular {
+            let reflected = normal * (2. * normal.dot(l)) - l;
+            let cos_beta = reflected.dot(to_camera) / (reflected.length() * to_camera.length());
+            if cos_beta > 0. {
+                factor += cos_beta.powf(s);
+            }
+        }
+
+        let factor = factor * visibility(i);
+        for k in 0..3 {
+            intensity[k] += rgb[k] * factor;
+        }
+    }
+    intensity
+}
+
+// The normal encoded in `texel` of a normal map (see `Model::normal_map`), on
+// a surface with the given normal and tangent. `handedness` tells on which
+// side of the tangent the direction of increasing v is (see
+// `Triangle::handedness`).
+pub fn perturb_normal(normal: Point3, tangent: Point3, handedness: f32, texel: u32) -> Point3 {
+    let n = normal.normalize();
+    // Interpolation can leave the tangent not quite perpendicular to the
+    // normal, or even degenerate if there are no texture coordinates.
+    let t = tangent - n * n.dot(tangent);
+    if t.length() < 1e-6 {
+        return n;
+    }
+    let t = t.normalize();
+    let b = n.cross(t) * handedness;
+    let [x, y, z] = [16, 8, 0].map(|shift| ((texel >> shift) & 0xFF) as f32 / 127.5 - 1.);
+    (t * x + b * y + n * z).normalize()
+}
+
+#[test]
+fn test_compute_lighting() {
+    let lights = [
+        Light::ambient(0.2),
+        Light::directional(0.2, Point3::new(-1., 0., 1.)),
+        Light::point(0.6, Point3::new(-3., 2., -10.)),
+    ];
+    let point = Point3::new(0., 0., 5.);
+
+    // Facing the camera: lit by the point light, but not the directional one.
+    let towards_camera = Point3::new(0., 0., -1.);
+    let lit = |_| 1.;
+    let [matte, _, _] = compute_lighting(point, towards_camera, None, &lights, lit);
+    assert!((matte - (0.2 + 0.6 * 15. / 238_f32.sqrt())).abs() < 1e-5);
+    assert!(compute_lighting(point, towards_camera, Some(10.), &lights, lit)[0] > matte);
+
+    // In the shadow of the point light.
+    let shadowed = |i| if i == 2 { 0. } else { 1. };
+    let [matte, _, _] = compute_lighting(point, towards_camera, None, &lights, shadowed);
+    assert!((matte - 0.2).abs() < 1e-5);
+
+    // Facing away: lit by the directional light only.
+    let away = Point3::new(0., 0., 1.);
+    let [matte, _, _] = compute_lighting(point, away, None, &lights, lit);
+    assert!((matte - (0.2 + 0.2 / 2_f32.sqrt())).abs() < 1e-5);
+
+    // Colored lights only affect their own channels.
+    let lights = [Light::ambient(0.5).with_color(Color::yellow().into())];
+    assert_eq!(
+        compute_lighting(point, away, None, &lights, lit),
+        [0.5, 0.5, 0.]
+    );
+}
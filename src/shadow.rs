@@ -0,0 +1,326 @@
+use crate::clip::*;
+use crate::light::*;
+use crate::math::*;
+use crate::scene::Triangle;
+use crate::Canvas;
+
+// Geometry closer to a point light than this isn't rendered into its
+// shadow map, so it doesn't cast shadows.
+const NEAR: f32 = 0.05;
+
+/// How shadow maps are sampled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShadowFilter {
+    // A point is either in shadow or not, which gives jagged edges.
+    Hard,
+    // Percentage-closer filtering: the fraction of the texels within this
+    // radius that don't occlude the point, which softens the edges.
+    Pcf(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ShadowOptions {
+    // The width and height of each shadow map, in texels.
+    pub resolution: usize,
+    // How far points are moved along the normal of their surface, in
+    // texels, before looking them up, to keep surfaces from shadowing
+    // themselves ("shadow acne"). Too much detaches shadows from the objects
+    // casting them.
+    pub bias: f32,
+    pub filter: ShadowFilter,
+}
+
+impl Default for ShadowOptions {
+    fn default() -> Self {
+        Self {
+            resolution: 512,
+            bias: 1.5,
+            filter: ShadowFilter::Pcf(1),
+        }
+    }
+}
+
+/// The depth of the scene as seen from a light, which tells which points
+/// the light reaches. Directional lights have a single orthographic map
+/// covering the scene; point lights have a cube map, i.e. six perspective
+/// maps with a 90 degree field of view, one along each axis.
+pub struct ShadowMap {
+    faces: Vec<Face>,
+    options: ShadowOptions,
+}
+
+// One depth map, rendered from `origin` looking along `axes[2]`.
+struct Face {
+    origin: Point3,
+    axes: [Point3; 3],
+    perspective: bool,
+    // Texels per unit of distance on the projection plane.
+    scale: f32,
+    // For orthographic maps: depths are stored as `far - z`, which is linear
+    // in screen space like 1/z is for perspective ones, and positive.
+    far: f32,
+    depth: Canvas,
+}
+
+impl Face {
+    fn new(
+        origin: Point3,
+        direction: Point3,
+        perspective: bool,
+        scale: f32,
+        far: f32,
+        resolution: usize,
+    ) -> Self {
+        let w = direction.normalize();
+        let up = if w.y.abs() < 0.99 {
+            Point3::new(0., 1., 0.)
+        } else {
+            Point3::new(1., 0., 0.)
+        };
+        let u = w.cross(up).normalize();
+        let v = u.cross(w);
+        Self {
+            origin,
+            axes: [u, v, w],
+            perspective,
+            scale,
+            far,
+            depth: Canvas::new(resolution, resolution),
+        }
+    }
+
+    fn to_light(&self, p: Point3) -> Point3 {
+        let d = p - self.origin;
+        Point3::new(
+            d.dot(self.axes[0]),
+            d.dot(self.axes[1]),
+            d.dot(self.axes[2]),
+        )
+    }
+
+    // The position of a point in light space on the map, in texels.
+    fn project(&self, q: Point3) -> (f32, f32) {
+        if self.perspective {
+            (q.x / q.z * self.scale, q.y / q.z * self.scale)
+        } else {
+            (q.x * self.scale, q.y * self.scale)
+        }
+    }
+
+    fn key(&self, z: f32) -> f32 {
+        if self.perspective {
+            1. / z
+        } else {
+            self.far - z
+        }
+    }
+
+    fn render(&mut self, casters: &[(Vec<Point3>, &[Triangle])]) {
+        let planes = if self.perspective {
+            let h = std::f32::consts::FRAC_1_SQRT_2;
+            vec![
+                Plane::new(Point3::new(0., 0., 1.), -NEAR),
+                Plane::new(Point3::new(h, 0., h), 0.),
+                Plane::new(Point3::new(-h, 0., h), 0.),
+                Plane::new(Point3::new(0., h, h), 0.),
+                Plane::new(Point3::new(0., -h, h), 0.),
+            ]
+        } else {
+            Vec::new()
+        };
+        for (vertices, triangles) in casters {
+            let mut local: Vec<Point3> = vertices.iter().map(|p| self.to_light(*p)).collect();
+            let mut triangles = triangles.to_vec();
+            for plane in &planes {
+                triangles = clip_triangles(&triangles, &mut local, plane);
+            }
+            let projected: Vec<Point2> = local
+                .iter()
+                .map(|q| {
+                    let (x, y) = self.project(*q);
+                    Point2::new(x.round() as i32, y.round() as i32)
+                })
+                .collect();
+            for t in &triangles {
+                let (i0, i1, i2) = t.v;
+                let keys = [i0, i1, i2].map(|i| self.key(local[i].z));
+                self.depth.draw_filled_triangle_with_depth(
+                    &projected[i0],
+                    &projected[i1],
+                    &projected[i2],
+                    keys,
+                    0,
+                );
+            }
+        }
+    }
+
+    // The fraction of the texels around the point's projection where
+    // nothing is in front of it.
+    fn visibility(&self, p: Point3, normal: Point3, options: &ShadowOptions) -> f32 {
+        let r = match options.filter {
+            ShadowFilter::Hard => 0,
+            ShadowFilter::Pcf(radius) => radius as i32,
+        };
+        // Texels cover more of the surface the further they are. Filtering
+        // compares the point with texels further away, so it needs more bias.
+        let z = self.to_light(p).z;
+        let texel = if self.perspective {
+            z.max(NEAR) / self.scale
+        } else {
+            1. / self.scale
+        };
+        let offset = options.bias * texel * (r + 1) as f32;
+        let q = self.to_light(p + normal.normalize() * offset);
+        if self.perspective && q.z < NEAR {
+            return 1.;
+        }
+        let (x, y) = self.project(q);
+        let (x, y) = (x.round() as i32, y.round() as i32);
+        let mut lit = 0;
+        for dy in -r..r + 1 {
+            for dx in -r..r + 1 {
+                let occluder = match self.depth.depth_at(x + dx, y + dy) {
+                    Some(key) if key > 0. => {
+                        if self.perspective {
+                            1. / key
+                        } else {
+                            self.far - key
+                        }
+                    }
+                    _ => f32::MAX,
+                };
+                if q.z <= occluder {
+                    lit += 1;
+                }
+            }
+        }
+        lit as f32 / ((2 * r + 1) * (2 * r + 1)) as f32
+    }
+}
+
+impl ShadowMap {
+    /// Renders the shadow map of `light` for the given shadow casters, i.e.
+    /// triangles along with the vertices they index in the same space as
+    /// the light. `bounds` is a sphere containing them all. Ambient lights
+    /// don't cast shadows, so there is no map for them.
+    pub fn new(
+        light: &Light,
+        casters: &[(Vec<Point3>, &[Triangle])],
+        bounds: (Point3, f32),
+        options: ShadowOptions,
+    ) -> Option<Self> {
+        let half = options.resolution as f32 / 2.;
+        let (center, radius) = bounds;
+        let mut faces = match light.kind {
+            LightKind::Ambient => return None,
+            LightKind::Directional(direction) => {
+                // Look at the whole bounding sphere, from just outside it.
+                let w = -direction.normalize();
+                let origin = center - w * radius;
+                vec![Face::new(
+                    origin,
+                    w,
+                    false,
+                    half / radius,
+                    2. * radius + 1.,
+                    options.resolution,
+                )]
+            }
+            LightKind::Point(position) => [
+                Point3::new(1., 0., 0.),
+                Point3::new(-1., 0., 0.),
+                Point3::new(0., 1., 0.),
+                Point3::new(0., -1., 0.),
+                Point3::new(0., 0., 1.),
+                Point3::new(0., 0., -1.),
+            ]
+            .map(|axis| Face::new(position, axis, true, half, 0., options.resolution))
+            .into_iter()
+            .collect(),
+        };
+        for face in &mut faces {
+            face.render(casters);
+        }
+        Some(Self { faces, options })
+    }
+
+    /// How much of the light reaches `p`, on a surface with the given
+    /// `normal`, from 0 (in shadow) to 1 (lit).
+    pub fn visibility(&self, p: Point3, normal: Point3) -> f32 {
+        let face = if self.faces.len() == 1 {
+            &self.faces[0]
+        } else {
+            // The face of the cube map whose axis is closest to the direction
+            // from the light to the point.
+            let d = p - self.faces[0].origin;
+            let (ax, ay, az) = (d.x.abs(), d.y.abs(), d.z.abs());
+            let i = if ax >= ay && ax >= az {
+                if d.x > 0. {
+                    0
+                } else {
+                    1
+                }
+            } else if ay >= az {
+                if d.y > 0. {
+                    2
+                } else {
+                    3
+                }
+            } else if d.z > 0. {
+                4
+            } else {
+                5
+            };
+            &self.faces[i]
+        };
+        face.visibility(p, normal, &self.options)
+    }
+}
+
+#[test]
+fn test_shadow_map() {
+    // A square floating above the origin, lit from straight above.
+    let vertices = vec![
+        Point3::new(-1., 1., -1.),
+        Point3::new(1., 1., -1.),
+        Point3::new(1., 1., 1.),
+        Point3::new(-1., 1., 1.),
+    ];
+    let triangles = [
+        Triangle::new((0, 1, 2), 0u32),
+        Triangle::new((0, 2, 3), 0u32),
+    ];
+    let casters = [(vertices, &triangles[..])];
+    let bounds = (Point3::new(0., 0., 0.), 3.);
+    let up = Point3::new(0., 1., 0.);
+    let options = ShadowOptions {
+        filter: ShadowFilter::Hard,
+        ..Default::default()
+    };
+
+    let sun = Light::directional(1., Point3::new(0., 1., 0.));
+    let map = ShadowMap::new(&sun, &casters, bounds, options).unwrap();
+    assert_eq!(map.visibility(Point3::new(0., 0., 0.), up), 0.);
+    assert_eq!(map.visibility(Point3::new(2., 0., 0.), up), 1.);
+    // The square doesn't shadow itself, nor what's above it.
+    assert_eq!(map.visibility(Point3::new(0.5, 1., 0.5), up), 1.);
+    assert_eq!(map.visibility(Point3::new(0., 2., 0.), up), 1.);
+
+    let bulb = Light::point(1., Point3::new(0., 2., 0.));
+    let map = ShadowMap::new(&bulb, &casters, bounds, options).unwrap();
+    assert_eq!(map.visibility(Point3::new(1.5, 0., 0.), up), 0.);
+    assert_eq!(map.visibility(Point3::new(2.5, 0., 0.), up), 1.);
+    assert_eq!(map.visibility(Point3::new(3., 3., 0.), up), 1.);
+
+    // Filtering softens the edges of the shadow.
+    let options = ShadowOptions {
+        filter: ShadowFilter::Pcf(2),
+        ..options
+    };
+    let map = ShadowMap::new(&sun, &casters, bounds, options).unwrap();
+    let edge = map.visibility(Point3::new(1., 0., 0.), up);
+    assert!(0. < edge && edge < 1.);
+
+    assert!(ShadowMap::new(&Light::ambient(1.), &casters, bounds, options).is_none());
+}
@@ -0,0 +1,155 @@
+/// When the stencil test passes, comparing the reference value of the
+/// `StencilState` with what's in the stencil buffer, both masked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StencilFunc {
+    Always,
+    Never,
+    // E.g. `Less` passes if the reference is less than the stored value.
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// How the stencil buffer is updated for a sample, depending on the
+/// outcome of the stencil and depth tests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StencilOp {
+    Keep,
+    Zero,
+    // The reference value.
+    Replace,
+    // Both clamp instead of wrapping around.
+    Increment,
+    Decrement,
+    Invert,
+}
+
+/// What `Canvas` does with the stencil buffer when drawing: samples that
+/// fail the test aren't drawn. The default passes everything and never
+/// writes to the buffer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StencilState {
+    pub func: StencilFunc,
+    pub reference: u8,
+    // The bits that are compared by the test.
+    pub read_mask: u8,
+    // The bits that are changed by the ops.
+    pub write_mask: u8,
+    // When the stencil test fails.
+    pub fail: StencilOp,
+    // When the stencil test passes but the depth test fails.
+    pub depth_fail: StencilOp,
+    // When both pass, or just the stencil test for drawing without depth.
+    pub pass: StencilOp,
+}
+
+impl Default for StencilState {
+    fn default() -> Self {
+        Self {
+            func: StencilFunc::Always,
+            reference: 0,
+            read_mask: 0xFF,
+            write_mask: 0xFF,
+            fail: StencilOp::Keep,
+            depth_fail: StencilOp::Keep,
+            pass: StencilOp::Keep,
+        }
+    }
+}
+
+impl StencilState {
+    // Sets the bits of `reference` wherever something is drawn.
+    pub fn write(reference: u8) -> Self {
+        Self {
+            reference,
+            write_mask: reference,
+            pass: StencilOp::Replace,
+            ..Default::default()
+        }
+    }
+
+    // Only draws where the bits of `mask` are set to those of `reference`
+    // (or, if `equal` is false, where they aren't).
+    pub fn only_where(equal: bool, reference: u8, mask: u8) -> Self {
+        Self {
+            func: if equal {
+                StencilFunc::Equal
+            } else {
+                StencilFunc::NotEqual
+            },
+            reference,
+            read_mask: mask,
+            ..Default::default()
+        }
+    }
+
+    // Whether the state has any effect, so drawing can skip the stencil
+    // buffer otherwise.
+    pub fn is_enabled(&self) -> bool {
+        self.func != StencilFunc::Always
+            || (self.write_mask != 0
+                && [self.fail, self.depth_fail, self.pass]
+                    .iter()
+                    .any(|op| *op != StencilOp::Keep))
+    }
+
+    pub fn test(&self, value: u8) -> bool {
+        let (r, v) = (self.reference & self.read_mask, value & self.read_mask);
+        match self.func {
+            StencilFunc::Always => true,
+            StencilFunc::Never => false,
+            StencilFunc::Equal => r == v,
+            StencilFunc::NotEqual => r != v,
+            StencilFunc::Less => r < v,
+            StencilFunc::LessOrEqual => r <= v,
+            StencilFunc::Greater => r > v,
+            StencilFunc::GreaterOrEqual => r >= v,
+        }
+    }
+
+    // The new stencil value after applying `op` to `value`.
+    pub fn apply(&self, op: StencilOp, value: u8) -> u8 {
+        let new = match op {
+            StencilOp::Keep => value,
+            StencilOp::Zero => 0,
+            StencilOp::Replace => self.reference,
+            StencilOp::Increment => value.saturating_add(1),
+            StencilOp::Decrement => value.saturating_sub(1),
+            StencilOp::Invert => !value,
+        };
+        (value & !self.write_mask) | (new & self.write_mask)
+    }
+}
+
+#[test]
+fn test_stencil_state() {
+    let state = StencilState::default();
+    assert!(!state.is_enabled());
+    assert!(state.test(42));
+    assert_eq!(state.apply(state.pass, 42), 42);
+
+    // Marking only changes the bits of the reference.
+    let mark = StencilState::write(0x80);
+    assert!(mark.is_enabled());
+    assert_eq!(mark.apply(mark.pass, 0x05), 0x85);
+    let inside = StencilState::only_where(true, 0x80, 0x80);
+    assert!(inside.test(0x85));
+    assert!(!inside.test(0x05));
+    assert!(StencilState::only_where(false, 0x80, 0x80).test(0x05));
+
+    let state = StencilState {
+        func: StencilFunc::Less,
+        reference: 2,
+        write_mask: 0x0F,
+        ..Default::default()
+    };
+    assert!(state.test(3));
+    assert!(!state.test(2));
+    assert_eq!(state.apply(StencilOp::Increment, 0xFF), 0xFF);
+    assert_eq!(state.apply(StencilOp::Decrement, 0), 0);
+    assert_eq!(state.apply(StencilOp::Invert, 0xA5), 0xAA);
+    assert_eq!(state.apply(StencilOp::Zero, 0xA5), 0xA0);
+}
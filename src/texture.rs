@@ -0,0 +1,298 @@
+use crate::math::*;
+
+/// How texture coordinates are interpolated across a triangle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextureMapping {
+    // Interpolates u and v linearly in screen space. Cheap, but textures
+    // look warped on triangles that aren't parallel to the projection plane.
+    Affine,
+    // Interpolates u/z and v/z, which are linear in screen space, and
+    // divides by the interpolated 1/z, as in Chapter 14.
+    PerspectiveCorrect,
+}
+
+/// How a texture is sampled between and across its texels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextureFilter {
+    // The nearest texel of the nearest mip level. Blocky up close.
+    Nearest,
+    // Blends the four nearest texels of the nearest mip level. Smooth up
+    // close, but the switch from one level to the next can be visible.
+    Bilinear,
+    // Blends bilinear samples of the two nearest mip levels.
+    Trilinear,
+}
+
+/// A bitmap image whose texels are packed as 0x00RRGGBB, row by row, starting
+/// from the top.
+pub struct Texture {
+    pub width: usize,
+    pub height: usize,
+    pub texels: Vec<u32>,
+    // Smaller and smaller versions of the texture, each half the size of the
+    // previous one, down to 1x1. Sampling them instead of the full texture
+    // when it's far away keeps it from shimmering.
+    mips: Vec<MipLevel>,
+}
+
+struct MipLevel {
+    width: usize,
+    height: usize,
+    texels: Vec<u32>,
+}
+
+// A level of a texture: the texture itself, or one of its mips.
+#[derive(Clone, Copy)]
+struct Level<'a> {
+    width: usize,
+    height: usize,
+    texels: &'a [u32],
+}
+
+impl Level<'_> {
+    // Half the size, rounded up, with each texel the average of the (up to)
+    // four texels it covers.
+    fn downsample(&self) -> MipLevel {
+        let (width, height) = (self.width.div_ceil(2), self.height.div_ceil(2));
+        let texels = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width * 2, i / width * 2);
+                let xs = [x, (x + 1).min(self.width - 1)];
+                let ys = [y, (y + 1).min(self.height - 1)];
+                let texels = ys
+                    .iter()
+                    .flat_map(|y| xs.map(|x| self.texels[y * self.width + x]));
+                mix(texels.map(|t| (t, 0.25)))
+            })
+            .collect();
+        MipLevel {
+            width,
+            height,
+            texels,
+        }
+    }
+
+    fn texel(&self, x: i32, y: i32) -> u32 {
+        let x = x.clamp(0, self.width as i32 - 1) as usize;
+        let y = y.clamp(0, self.height as i32 - 1) as usize;
+        self.texels[y * self.width + x]
+    }
+
+    fn nearest(&self, u: f32, v: f32) -> u32 {
+        let x = (u * self.width as f32).floor() as i32;
+        let y = (v * self.height as f32).floor() as i32;
+        self.texel(x, y)
+    }
+
+    // Texel centers are at half-integer coordinates, so (u, v) is between
+    // the texels around (u * width - 0.5, v * height - 0.5).
+    fn bilinear(&self, u: f32, v: f32) -> u32 {
+        let x = u * self.width as f32 - 0.5;
+        let y = v * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i32, y0 as i32);
+        mix([
+            (self.texel(x0, y0), (1. - fx) * (1. - fy)),
+            (self.texel(x0 + 1, y0), fx * (1. - fy)),
+            (self.texel(x0, y0 + 1), (1. - fx) * fy),
+            (self.texel(x0 + 1, y0 + 1), fx * fy),
+        ])
+    }
+}
+
+// The weighted sum of the given colors, channel by channel.
+pub fn mix(colors: impl IntoIterator<Item = (u32, f32)>) -> u32 {
+    let mut sum = [0.; 3];
+    for (color, weight) in colors {
+        for (k, channel) in sum.iter_mut().enumerate() {
+            *channel += ((color >> (16 - 8 * k)) & 0xFF) as f32 * weight;
+        }
+    }
+    sum.iter()
+        .fold(0, |acc, c| acc << 8 | c.round().clamp(0., 255.) as u32)
+}
+
+impl Texture {
+    // Panics if the texture is empty, or `texels` doesn't have `width` by
+    // `height` texels.
+    pub fn new(width: usize, height: usize, texels: Vec<u32>) -> Self {
+        assert!(
+            width > 0 && height > 0,
+            "empty {}x{} texture",
+            width,
+            height
+        );
+        assert_eq!(texels.len(), width * height);
+        let mut texture = Self {
+            width,
+            height,
+            texels,
+            mips: Vec::new(),
+        };
+        loop {
+            let level = texture.level(texture.mips.len());
+            if level.width <= 1 && level.height <= 1 {
+                break;
+            }
+            let mip = level.downsample();
+            texture.mips.push(mip);
+        }
+        texture
+    }
+
+    fn level(&self, i: usize) -> Level<'_> {
+        match i {
+            0 => Level {
+                width: self.width,
+                height: self.height,
+                texels: &self.texels,
+            },
+            i => {
+                let mip = &self.mips[i - 1];
+                Level {
+                    width: mip.width,
+                    height: mip.height,
+                    texels: &mip.texels,
+                }
+            }
+        }
+    }
+
+    // A `size` by `size` texture with `squares` by `squares` alternating
+    // squares of colors `c0` and `c1`.
+    pub fn checkerboard(size: usize, squares: usize, c0: u32, c1: u32) -> Self {
+        let square_size = (size / squares).max(1);
+        let texels = (0..size * size)
+            .map(|i| {
+                let (x, y) = (i % size / square_size, i / size / square_size);
+                if (x + y) % 2 == 0 {
+                    c0
+                } else {
+                    c1
+                }
+            })
+            .collect();
+        Self::new(size, size, texels)
+    }
+
+    // A normal map (see `Model::normal_map`) for a surface whose height at
+    // each texel is given by `heights`, row by row. `strength` scales how
+    // steep the slopes are.
+    pub fn normal_map(width: usize, height: usize, heights: &[f32], strength: f32) -> Self {
+        assert_eq!(heights.len(), width * height);
+        let h = |x: usize, y: usize| heights[y.min(height - 1) * width + x.min(width - 1)];
+        let texels = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                // Central differences, clamped at the edges.
+                let du = (h(x + 1, y) - h(x.saturating_sub(1), y)) / 2.;
+                let dv = (h(x, y + 1) - h(x, y.saturating_sub(1))) / 2.;
+                let n = Point3::new(-du * strength, -dv * strength, 1.).normalize();
+                [n.x, n.y, n.z]
+                    .iter()
+                    .fold(0, |acc, c| acc << 8 | ((c + 1.) * 127.5).round() as u32)
+            })
+            .collect();
+        Self::new(width, height, texels)
+    }
+
+    // The texel nearest to (u, v), where (0, 0) is the top left corner and
+    // (1, 1) the bottom right one. Coordinates outside that range are clamped.
+    pub fn get_texel(&self, u: f32, v: f32) -> u32 {
+        let x = ((u * self.width as f32) as usize).min(self.width - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height - 1);
+        self.texels[y * self.width + x]
+    }
+
+    // The number of mip levels, including the texture itself.
+    pub fn levels(&self) -> usize {
+        self.mips.len() + 1
+    }
+
+    // The mip level to sample for a pixel across which the texture
+    // coordinates change by `dx` = (du/dx, dv/dx) horizontally and `dy`
+    // vertically: 0 for the texture itself, when a pixel covers a texel or
+    // less, 1 when it covers two texels, and so on.
+    pub fn lod(&self, dx: (f32, f32), dy: (f32, f32)) -> f32 {
+        let (w, h) = (self.width as f32, self.height as f32);
+        let texels = |(du, dv): (f32, f32)| ((du * w).powi(2) + (dv * h).powi(2)).sqrt();
+        texels(dx).max(texels(dy)).max(1.).log2()
+    }
+
+    // The color of the texture at (u, v), like `get_texel`, at mip level
+    // `lod` (see `lod`).
+    pub fn sample(&self, u: f32, v: f32, lod: f32, filter: TextureFilter) -> u32 {
+        let max_level = (self.levels() - 1) as f32;
+        let lod = lod.clamp(0., max_level);
+        match filter {
+            TextureFilter::Nearest => self.level(lod.round() as usize).nearest(u, v),
+            TextureFilter::Bilinear => self.level(lod.round() as usize).bilinear(u, v),
+            TextureFilter::Trilinear => {
+                let (i, t) = (lod.floor() as usize, lod.fract());
+                let near = self.level(i).bilinear(u, v);
+                if t == 0. {
+                    return near;
+                }
+                let far = self.level(i + 1).bilinear(u, v);
+                mix([(near, 1. - t), (far, t)])
+            }
+        }
+    }
+}
+
+#[test]
+fn test_get_texel() {
+    let texture = Texture::checkerboard(4, 2, 1, 2);
+    assert_eq!(
+        texture.texels,
+        vec![1, 1, 2, 2, 1, 1, 2, 2, 2, 2, 1, 1, 2, 2, 1, 1]
+    );
+    assert_eq!(texture.get_texel(0., 0.), 1);
+    assert_eq!(texture.get_texel(0.6, 0.), 2);
+    assert_eq!(texture.get_texel(1., 1.), 1);
+    assert_eq!(texture.get_texel(-3., 0.9), 2);
+}
+
+#[test]
+#[should_panic]
+fn test_empty_texture() {
+    Texture::new(0, 4, Vec::new());
+}
+
+#[test]
+fn test_sample() {
+    let texture = Texture::checkerboard(4, 2, 0x000000, 0xFFFFFF);
+    assert_eq!(texture.levels(), 3);
+
+    // Up close, nearest sampling is blocky and bilinear sampling smooth.
+    let (u, v) = (0.5, 0.25);
+    assert_eq!(texture.sample(u, v, 0., TextureFilter::Nearest), 0xFFFFFF);
+    assert_eq!(texture.sample(u, v, 0., TextureFilter::Bilinear), 0x808080);
+    assert_eq!(texture.sample(0.125, 0.125, 0., TextureFilter::Bilinear), 0);
+
+    // Far away, the squares average out.
+    assert_eq!(
+        texture.sample(0.1, 0.1, 2., TextureFilter::Nearest),
+        0x808080
+    );
+    assert_eq!(
+        texture.sample(0.1, 0.1, 9., TextureFilter::Trilinear),
+        0x808080
+    );
+    // Halfway between the first two levels.
+    assert_eq!(
+        texture.sample(0.125, 0.125, 0.5, TextureFilter::Trilinear),
+        mix([
+            (0, 0.5),
+            (
+                texture.sample(0.125, 0.125, 1., TextureFilter::Bilinear),
+                0.5
+            )
+        ])
+    );
+
+    // A pixel covering four texels across.
+    assert_eq!(texture.lod((1., 0.), (0., 0.25)), 2.);
+    assert_eq!(texture.lod((0.01, 0.), (0., 0.01)), 0.);
+}